@@ -0,0 +1,166 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
+use hydra_sentinel::model::System;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::BTreeSet, path::Path, process::Command};
+
+const SSH_HOST_KEY: &str = "/etc/ssh/ssh_host_ed25519_key.pub";
+
+/// Machine facts discovered from the local Nix installation and host
+#[derive(Debug, Default)]
+pub struct Detected {
+    pub systems: BTreeSet<System>,
+    pub supported_features: BTreeSet<String>,
+    pub max_jobs: Option<u32>,
+    pub public_host_key: Option<String>,
+}
+
+pub fn detect() -> Detected {
+    let detected = from_nix_config(nix_config(), public_host_key(Path::new(SSH_HOST_KEY)));
+    tracing::info!(?detected, "Detected machine spec");
+    detected
+}
+
+fn from_nix_config(config: anyhow::Result<NixConfig>, public_host_key: Option<String>) -> Detected {
+    let mut detected = Detected {
+        max_jobs: std::thread::available_parallelism()
+            .ok()
+            .and_then(|n| u32::try_from(n.get()).ok()),
+        public_host_key,
+        ..Default::default()
+    };
+
+    match config {
+        Ok(config) => {
+            detected.systems = config.systems();
+            detected.supported_features = config.system_features.value;
+            // 0 means builds only run remotely, so the CPU count is a better guess
+            if let Some(Setting { value: max_jobs }) = config.max_jobs.filter(|s| s.value > 0) {
+                detected.max_jobs = Some(max_jobs);
+            }
+        }
+        Err(err) => {
+            tracing::warn!(
+                ?err,
+                "Failed to read Nix config, falling back to host system"
+            );
+            detected.systems.extend(host_system());
+        }
+    }
+    detected
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct NixConfig {
+    system: Setting<String>,
+    extra_platforms: Setting<Vec<String>>,
+    system_features: Setting<BTreeSet<String>>,
+    max_jobs: Option<Setting<u32>>,
+}
+
+#[derive(Deserialize)]
+struct Setting<T> {
+    value: T,
+}
+
+impl NixConfig {
+    fn systems(&self) -> BTreeSet<System> {
+        std::iter::once(&self.system.value)
+            .chain(&self.extra_platforms.value)
+            .filter_map(|system| {
+                serde_json::from_value(Value::String(system.clone()))
+                    .inspect_err(|_| tracing::debug!("Ignoring unsupported system {system}"))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+fn nix_config() -> anyhow::Result<NixConfig> {
+    // `nix config show` superseded `nix show-config` in Nix 2.20
+    let mut last_err = None;
+    for args in [&["config", "show"][..], &["show-config"][..]] {
+        let output = Command::new("nix")
+            .args(["--extra-experimental-features", "nix-command"])
+            .args(args)
+            .arg("--json")
+            .output()?;
+        if output.status.success() {
+            return Ok(serde_json::from_slice(&output.stdout)?);
+        }
+        last_err = Some(anyhow::anyhow!(
+            "nix {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Err(last_err.expect("at least one command to be attempted"))
+}
+
+fn host_system() -> Option<System> {
+    let system =
+        format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS).replace("-macos", "-darwin");
+    serde_json::from_value(Value::String(system)).ok()
+}
+
+/// The base64-encoded public host key, in the format expected by the machines file
+fn public_host_key(path: &Path) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(key) => Some(BASE64_STANDARD.encode(key.trim())),
+        Err(err) => {
+            tracing::debug!(?err, "Failed to read SSH host key {path:?}");
+            None
+        }
+    }
+}
+
+/// 1, 5 and 15 minute load averages
+#[cfg(unix)]
+pub fn load_average() -> Option<[f64; 3]> {
+    let mut load_average = [0f64; 3];
+    // SAFETY: the buffer holds the 3 samples requested
+    let n = unsafe { libc::getloadavg(load_average.as_mut_ptr(), 3) };
+    (n == 3).then_some(load_average)
+}
+
+#[cfg(not(unix))]
+pub fn load_average() -> Option<[f64; 3]> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nix_config() {
+        let config = serde_json::from_str(include_str!("../test/nix-config.json")).unwrap();
+        let detected = from_nix_config(Ok(config), None);
+        assert_eq!(
+            detected.systems,
+            [System::X86_64Linux, System::Aarch64Linux, System::I686Linux].into()
+        );
+        assert_eq!(
+            detected.supported_features,
+            [
+                "benchmark",
+                "big-parallel",
+                "gccarch-x86-64-v3",
+                "kvm",
+                "nixos-test"
+            ]
+            .map(str::to_string)
+            .into()
+        );
+        assert_eq!(detected.max_jobs, Some(8));
+    }
+
+    #[test]
+    fn fall_back_to_host_system() {
+        let detected = from_nix_config(Err(anyhow::anyhow!("nix not found")), None);
+        assert_eq!(detected.systems, host_system().into_iter().collect());
+        assert!(detected.supported_features.is_empty());
+        assert!(detected.max_jobs.is_some());
+    }
+}
@@ -1,103 +1,405 @@
-use crate::rate_limiter::RateLimiter;
+use crate::{detect::Detected, rate_limiter::RateLimiter};
+use anyhow::Context;
 use backon::{ExponentialBuilder, Retryable};
-use futures_util::{SinkExt, StreamExt};
-use hydra_sentinel::{SentinelMessage, shutdown_signal};
+use futures_util::{SinkExt, Stream, StreamExt};
+use hydra_sentinel::{
+    ClientMessage, PROTOCOL_VERSION, PowerAction, ServerMessage,
+    auth::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    model::{BuildMachineSpec, System},
+    shutdown_signal,
+    wake_on_lan::magic_packet,
+};
 use serde::Deserialize;
-use std::time::Duration;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::ControlFlow,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+};
+use tokio_tungstenite::{
+    Connector, connect_async_tls_with_config,
+    tungstenite::{
+        Error as WsError, client::IntoClientRequest, handshake::client::Request, protocol::Message,
+    },
+};
 
+mod detect;
 mod rate_limiter;
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Config {
+    /// `host:port` of the server, or a `ws://` or `wss://` URL
     server_addr: String,
     host_name: String,
+    /// PEM bundle of additional CA certificates to trust when connecting over `wss://`
+    ca_file: Option<PathBuf>,
+    /// PEM certificate presented to servers that require client certificates
+    client_cert_file: Option<PathBuf>,
+    /// PKCS #8 PEM private key for `client_cert_file`
+    client_key_file: Option<PathBuf>,
     #[serde(
         with = "humantime_serde",
         default = "Config::default_heartbeat_interval"
     )]
     heartbeat_interval: Duration,
+    /// File containing the secret shared with the server, used to sign connection requests
+    token_file: Option<PathBuf>,
+    /// Machine spec reported to the server, so builders needn't be listed in its `buildMachines`
+    #[serde(default)]
+    register: Registration,
+    /// Whether the server may suspend or shut down this machine when it's idle
+    #[serde(default)]
+    allow_power_down: bool,
+    /// Command suspending this machine, instead of `systemctl suspend` or `pmset sleepnow`
+    suspend_command: Option<Vec<String>>,
+    /// Command shutting down this machine, instead of `systemctl poweroff` or `shutdown -h now`
+    shutdown_command: Option<Vec<String>>,
+    /// Where wake-on-lan packets may be sent on behalf of the server
+    #[serde(default)]
+    wake_on_lan: WakeOnLanRelay,
+}
+
+/// The only address wake-on-lan packets are sent to on behalf of the server, so that the server
+/// can't send arbitrary datagrams from the builder's network
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct WakeOnLanRelay {
+    broadcast_address: IpAddr,
+    port: u16,
+}
+
+impl Default for WakeOnLanRelay {
+    fn default() -> Self {
+        Self {
+            broadcast_address: IpAddr::V4(Ipv4Addr::BROADCAST),
+            port: 9,
+        }
+    }
+}
+
+impl WakeOnLanRelay {
+    fn target(&self) -> SocketAddr {
+        SocketAddr::new(self.broadcast_address, self.port)
+    }
 }
 
 impl Config {
     fn default_heartbeat_interval() -> Duration {
         Duration::from_secs(30)
     }
+
+    fn request(&self) -> anyhow::Result<Request> {
+        let base_url = if self.server_addr.contains("://") {
+            self.server_addr.trim_end_matches('/').to_string()
+        } else {
+            format!("ws://{}", self.server_addr)
+        };
+        let mut request =
+            format!("{base_url}/ws?host_name={}", self.host_name).into_client_request()?;
+
+        if let Some(path) = &self.token_file {
+            let token = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read token {path:?}"))?;
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let headers = request.headers_mut();
+            headers.insert(TIMESTAMP_HEADER, timestamp.to_string().parse()?);
+            headers.insert(
+                SIGNATURE_HEADER,
+                auth::sign(token.trim().as_bytes(), &self.host_name, timestamp).parse()?,
+            );
+        }
+
+        Ok(request)
+    }
+
+    fn connector(&self) -> anyhow::Result<Option<Connector>> {
+        if self.ca_file.is_none() && self.client_cert_file.is_none() {
+            return Ok(None);
+        }
+
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(path) = &self.ca_file {
+            let bundle = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read CA bundle {path:?}"))?;
+            for cert in bundle
+                .split_inclusive("-----END CERTIFICATE-----")
+                .filter(|cert| cert.contains("-----BEGIN CERTIFICATE-----"))
+            {
+                builder.add_root_certificate(native_tls::Certificate::from_pem(cert.as_bytes())?);
+            }
+        }
+        match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = std::fs::read(cert_path)
+                    .with_context(|| format!("Failed to read client certificate {cert_path:?}"))?;
+                let key = std::fs::read(key_path)
+                    .with_context(|| format!("Failed to read client key {key_path:?}"))?;
+                builder.identity(native_tls::Identity::from_pkcs8(&cert, &key)?);
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("clientCertFile and clientKeyFile must be set together"),
+        }
+        Ok(Some(Connector::NativeTls(builder.build()?)))
+    }
+}
+
+/// Overrides for the machine spec reported to the server. Unset fields are detected from the
+/// local Nix config and host.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Registration {
+    ssh_user: Option<String>,
+    systems: Option<BTreeSet<System>>,
+    max_jobs: Option<u32>,
+    speed_factor: Option<u32>,
+    supported_features: Option<BTreeSet<String>>,
+    #[serde(default)]
+    mandatory_features: BTreeSet<String>,
+    public_host_key: Option<String>,
+    /// File containing the server's pre-shared registration token, if required
+    token_file: Option<PathBuf>,
+}
+
+impl Registration {
+    fn spec(&self, host_name: &str, detected: Detected) -> BuildMachineSpec {
+        BuildMachineSpec {
+            ssh_user: self.ssh_user.clone(),
+            host_name: host_name.to_string(),
+            systems: self.systems.clone().unwrap_or(detected.systems),
+            ssh_key: None,
+            max_jobs: self.max_jobs.or(detected.max_jobs),
+            speed_factor: self.speed_factor,
+            supported_features: self
+                .supported_features
+                .clone()
+                .unwrap_or(detected.supported_features),
+            mandatory_features: self.mandatory_features.clone(),
+            public_host_key: self.public_host_key.clone().or(detected.public_host_key),
+        }
+    }
+
+    fn message(&self, spec: &BuildMachineSpec) -> anyhow::Result<ClientMessage> {
+        let token = match &self.token_file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read registration token {path:?}"))?
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        };
+
+        Ok(ClientMessage::Register {
+            spec: spec.clone(),
+            token,
+        })
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     let config = hydra_sentinel::init::<Config>(&format!("{}=DEBUG", module_path!()))?;
 
+    let spec = config.register.spec(&config.host_name, detect::detect());
+
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
     let reconnect = RateLimiter::new(Duration::from_secs(30));
-    let run = async {
-        loop {
-            reconnect.throttle(|| run(&config)).await?;
+    loop {
+        let mut wait_shutdown = shutdown.clone();
+        tokio::select! {
+            _ = reconnect.wait() => {},
+            _ = wait_shutdown.wait_for(|&s| s) => break,
+        }
+        if run(&config, &spec, shutdown.clone()).await?.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn text(msg: ClientMessage) -> Message {
+    Message::text::<String>(msg.into())
+}
+
+async fn next_message(
+    receiver: &mut (impl Stream<Item = Result<Message, WsError>> + Unpin),
+) -> anyhow::Result<Option<ServerMessage>> {
+    while let Some(msg) = receiver.next().await {
+        match msg? {
+            // e.g. sent by a newer server
+            Message::Text(msg) => match ServerMessage::try_from(msg.as_str()) {
+                Ok(msg) => return Ok(Some(msg)),
+                Err(err) => tracing::warn!(?err, "Skipping unknown message {msg:?}"),
+            },
+            Message::Close(frame) => {
+                tracing::info!(?frame, "Server closed connection");
+                return Ok(None);
+            }
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) | Message::Binary(_) => {}
         }
-        #[allow(unreachable_code)]
-        anyhow::Ok(())
+    }
+    Ok(None)
+}
+
+/// Send a wake-on-lan packet on behalf of the server, to a machine on this builder's network
+async fn wake_on_lan(packet: &[u8], to: SocketAddr) -> anyhow::Result<()> {
+    let from_addr: SocketAddr = match to {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
     };
+    let socket = UdpSocket::bind(from_addr).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(packet, to).await?;
+    Ok(())
+}
 
-    let shutdown = shutdown_signal();
-    tokio::select! {
-        r = run => r,
-        _ = shutdown => Ok(()),
+/// Suspend or shut down this machine on behalf of the server
+async fn power_down(config: &Config, action: PowerAction) -> anyhow::Result<()> {
+    let configured = match action {
+        PowerAction::Suspend => &config.suspend_command,
+        PowerAction::Shutdown => &config.shutdown_command,
+    };
+    let command = match configured {
+        Some(command) => command.clone(),
+        None => {
+            let command: &[&str] = match action {
+                PowerAction::Suspend if cfg!(target_os = "macos") => &["pmset", "sleepnow"],
+                PowerAction::Shutdown if cfg!(target_os = "macos") => &["shutdown", "-h", "now"],
+                PowerAction::Suspend => &["systemctl", "suspend"],
+                PowerAction::Shutdown => &["systemctl", "poweroff"],
+            };
+            command.iter().map(|arg| arg.to_string()).collect()
+        }
+    };
+    let Some((program, args)) = command.split_first() else {
+        anyhow::bail!("Empty {action:?} command");
+    };
+    let status = tokio::process::Command::new(program)
+        .args(args)
+        .status()
+        .await
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !status.success() {
+        anyhow::bail!("{program} failed with {status}");
     }
+    Ok(())
 }
 
-async fn run(config: &Config) -> anyhow::Result<()> {
-    let (mut sender, mut receiver) = (|| async move {
+/// Run a single connection to the server, returning [`ControlFlow::Break`] on shutdown
+async fn run(
+    config: &Config,
+    spec: &BuildMachineSpec,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<ControlFlow<()>> {
+    let connect = (|| async move {
         tracing::info!("Connecting to server: {}...", config.server_addr);
-        let (stream, _response) = connect_async(format!(
-            "ws://{}/ws?host_name={}",
-            config.server_addr, config.host_name
-        ))
-        .await?;
+        let (stream, _response) =
+            connect_async_tls_with_config(config.request()?, None, false, config.connector()?)
+                .await?;
         tracing::info!("Connected");
         anyhow::Ok(stream)
     })
     .retry(&ExponentialBuilder::default().with_jitter())
-    .notify(|err, dur| tracing::error!(?err, "Connect failed, retrying in {dur:?}"))
-    .await?
-    .split();
+    .notify(|err, dur| tracing::error!(?err, "Connect failed, retrying in {dur:?}"));
+
+    let (mut sender, mut receiver) = tokio::select! {
+        r = connect => r?.split(),
+        _ = shutdown.wait_for(|&s| s) => return Ok(ControlFlow::Break(())),
+    };
+
+    sender
+        .send(text(ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+        .await?;
+    match next_message(&mut receiver)
+        .await
+        .with_context(|| format!("Server may not support protocol v{PROTOCOL_VERSION}"))?
+    {
+        Some(ServerMessage::Welcome {
+            protocol_version,
+            server_version,
+        }) => {
+            tracing::info!("Server {server_version} accepted protocol v{protocol_version}");
+        }
+        Some(ServerMessage::Reject { reason }) => {
+            anyhow::bail!("Server rejected connection: {reason}");
+        }
+        msg => anyhow::bail!("Expected welcome from server, got {msg:?}"),
+    }
+
+    sender.send(text(config.register.message(spec)?)).await?;
+
+    let (heartbeat_interval_tx, mut heartbeat_interval) = watch::channel(config.heartbeat_interval);
+    let (outbox, mut outbox_rx) = mpsc::unbounded_channel();
 
     let send_task = async move {
-        let mut interval = tokio::time::interval(config.heartbeat_interval);
+        let mut interval = tokio::time::interval(*heartbeat_interval.borrow());
         loop {
-            interval.tick().await;
-            sender.send(Message::Ping(Default::default())).await?;
+            tokio::select! {
+                // once the outbox is closed, nothing else is sent
+                biased;
+                msg = outbox_rx.recv() => match msg {
+                    Some(msg) => sender.send(text(msg)).await?,
+                    // the server is done with this connection, or this machine is powering down
+                    None => {
+                        let _ = sender.send(Message::Close(None)).await;
+                        return anyhow::Ok(ControlFlow::Continue(()));
+                    }
+                },
+                _ = interval.tick() => {
+                    let heartbeat = match detect::load_average() {
+                        Some(load_average) => text(ClientMessage::Load { load_average }),
+                        None => Message::Ping(Default::default()),
+                    };
+                    sender.send(heartbeat).await?;
+                }
+                Ok(()) = heartbeat_interval.changed() => {
+                    interval = tokio::time::interval(*heartbeat_interval.borrow_and_update());
+                }
+                _ = shutdown.wait_for(|&s| s) => {
+                    sender
+                        .send(text(ClientMessage::Goodbye {
+                            reason: Some("Client shutting down".to_string()),
+                        }))
+                        .await?;
+                    sender.send(Message::Close(None)).await?;
+                    return anyhow::Ok(ControlFlow::Break(()));
+                }
+            }
         }
-        #[allow(unreachable_code)]
-        anyhow::Ok(())
     };
 
     let recv_task = async move {
         let mut awake_handle = None;
 
-        while let Some(msg) = receiver.next().await {
-            match msg? {
-                Message::Text(msg) => {
-                    let keep_awake = match SentinelMessage::try_from(msg.as_str()) {
-                        Ok(SentinelMessage::KeepAwake(awake)) => awake,
-                        Err(err) => {
-                            tracing::warn!(?msg, ?err, "Failed to parse message");
-                            continue;
-                        }
-                    };
-
-                    if keep_awake == awake_handle.is_some() {
+        while let Some(msg) = next_message(&mut receiver).await? {
+            match msg {
+                ServerMessage::KeepAwake { awake, reason } => {
+                    if awake == awake_handle.is_some() {
                         continue;
                     }
 
-                    if keep_awake {
-                        tracing::info!("Server requested keep-awake");
+                    if awake {
+                        tracing::info!(?reason, "Server requested keep-awake");
                         awake_handle = Some(
                             keepawake::Builder::default()
                                 .display(false)
                                 .idle(true)
                                 .sleep(true)
-                                .reason("Build queued")
+                                .reason(reason.as_deref().unwrap_or("Build queued"))
                                 .app_name("Nix Hydra Builder")
                                 .app_reverse_domain("net.nregner.hydra-util")
                                 .create()?,
@@ -106,24 +408,92 @@ async fn run(config: &Config) -> anyhow::Result<()> {
                         tracing::info!("Server cancelled keep-awake");
                         awake_handle = None;
                     }
+                    let _ = outbox.send(ClientMessage::Status { awake });
                 }
-                Message::Close(_) => {
-                    tracing::info!("Server closed connection");
+                ServerMessage::Config {
+                    heartbeat_interval_secs,
+                } => {
+                    let interval = Duration::from_secs(heartbeat_interval_secs);
+                    tracing::debug!("Server set heartbeat interval to {interval:?}");
+                    let _ = heartbeat_interval_tx.send(interval);
+                }
+                ServerMessage::Shutdown { reason } => {
+                    tracing::info!(?reason, "Server requested disconnect");
                     break;
                 }
-                Message::Ping(_) => {}
-                Message::Pong(_) => {}
-                Message::Frame(_) => {}
-                Message::Binary(_) => {}
+                ServerMessage::WakeOnLan {
+                    mac_address,
+                    secure_on,
+                    to,
+                } => {
+                    let allowed = config.wake_on_lan.target();
+                    if to != allowed {
+                        tracing::warn!(
+                            "Refusing to send wake-on-lan packet to {to}, only {allowed} is allowed"
+                        );
+                        continue;
+                    }
+                    tracing::info!("Server requested wake-on-lan packet to {to}");
+                    let packet = magic_packet(&mac_address, secure_on.as_deref());
+                    if let Err(err) = wake_on_lan(&packet, to).await {
+                        tracing::error!(?err, "Failed to send wake-on-lan packet to {to}");
+                    }
+                }
+                ServerMessage::PowerDown { action, reason } => {
+                    if !config.allow_power_down {
+                        tracing::warn!(
+                            ?reason,
+                            "Ignoring request to {action:?}, allowPowerDown isn't set"
+                        );
+                        continue;
+                    }
+                    tracing::info!(?reason, "Server requested {action:?}");
+                    // said before powering down, as after a suspend it would only arrive on resume
+                    let _ = outbox.send(ClientMessage::Goodbye {
+                        reason: Some(format!("{action:?}")),
+                    });
+                    return anyhow::Ok(Some(action));
+                }
+                msg @ (ServerMessage::Welcome { .. } | ServerMessage::Reject { .. }) => {
+                    tracing::warn!(?msg, "Ignoring unexpected message");
+                }
             };
         }
 
-        #[allow(unreachable_code)]
-        anyhow::Ok(())
+        anyhow::Ok(None)
+    };
+
+    tokio::pin!(send_task);
+    let power_down_action = tokio::select! {
+        r = &mut send_task => return r,
+        r = recv_task => r?,
     };
+    // the receiving end dropped the outbox, so this sends what's left and closes the connection
+    let flow = send_task.await?;
+
+    if let Some(action) = power_down_action {
+        if let Err(err) = power_down(config, action).await {
+            tracing::error!(?err, "Failed to {action:?}");
+        }
+    }
+    Ok(flow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    tokio::select! {
-        r = send_task => r,
-        r = recv_task => r,
+    #[tokio::test]
+    async fn skip_unknown_messages() {
+        let mut receiver = futures_util::stream::iter([
+            Ok(Message::text(r#"{"type":"bogus"}"#)),
+            Ok(Message::text(r#"{"type":"shutdown","reason":null}"#)),
+        ]);
+        let msg = next_message(&mut receiver).await.unwrap();
+        assert!(matches!(
+            msg,
+            Some(ServerMessage::Shutdown { reason: None })
+        ));
+        assert!(next_message(&mut receiver).await.unwrap().is_none());
     }
 }
@@ -14,16 +14,7 @@ impl RateLimiter {
         }
     }
 
-    pub async fn throttle<Fut, F, T>(&self, f: F) -> T
-    where
-        Fut: std::future::Future<Output = T>,
-        F: FnOnce() -> Fut,
-    {
-        self.wait().await;
-        f().await
-    }
-
-    async fn wait(&self) {
+    pub async fn wait(&self) {
         let mut interval = self.interval.lock().await;
         interval.tick().await;
     }
@@ -0,0 +1,47 @@
+//! Builder authentication: the client signs its host name and the current time with a
+//! per-builder shared secret, sent as headers on the websocket upgrade request. The server
+//! accepts each signature only once, so a fresh one is needed for every connection.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const TIMESTAMP_HEADER: &str = "X-Sentinel-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Sentinel-Signature";
+
+fn mac(secret: &[u8], host_name: &str, timestamp: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take key of any size");
+    mac.update(format!("{host_name}:{timestamp}").as_bytes());
+    mac
+}
+
+/// Signature header value for `host_name` connecting at `timestamp` (seconds since the epoch)
+pub fn sign(secret: &[u8], host_name: &str, timestamp: u64) -> String {
+    let signature = mac(secret, host_name, timestamp).finalize().into_bytes();
+    format!("sha256={}", hex::encode(signature))
+}
+
+pub fn verify(secret: &[u8], host_name: &str, timestamp: u64, signature: &str) -> bool {
+    let Some(signature) = signature
+        .strip_prefix("sha256=")
+        .and_then(|hex| hex::decode(hex).ok())
+    else {
+        return false;
+    };
+    mac(secret, host_name, timestamp)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let signature = sign(b"hocus pocus", "bogus", 1712425681);
+        assert!(verify(b"hocus pocus", "bogus", 1712425681, &signature));
+        assert!(!verify(b"hocus pocus", "other", 1712425681, &signature));
+        assert!(!verify(b"hocus pocus", "bogus", 1712425682, &signature));
+        assert!(!verify(b"abracadabra", "bogus", 1712425681, &signature));
+    }
+}
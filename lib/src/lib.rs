@@ -2,29 +2,123 @@ use figment::{
     Figment,
     providers::{Env, Format, Json, Toml},
 };
+use model::BuildMachineSpec;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::net::SocketAddr;
 use tokio::signal;
 use tracing_subscriber::{EnvFilter, prelude::*, util::SubscriberInitExt};
 
-#[derive(Serialize, Deserialize)]
-pub enum SentinelMessage {
-    KeepAwake(bool),
+pub mod auth;
+pub mod model;
+pub mod wake_on_lan;
+
+/// Version of the websocket protocol spoken between client and server, bumped on incompatible
+/// changes
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Messages sent from a builder to the server
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ClientMessage {
+    /// First message on every connection
+    Hello {
+        protocol_version: u32,
+        client_version: String,
+    },
+
+    /// Announce the builder's machine spec, so that builders not listed in the server's
+    /// `buildMachines` can register themselves
+    Register {
+        spec: BuildMachineSpec,
+        /// Pre-shared registration token, if required by the server
+        token: Option<String>,
+    },
+
+    /// Whether the builder is currently holding a keep-awake lock
+    Status { awake: bool },
+
+    /// 1, 5 and 15 minute load averages of the builder
+    Load { load_average: [f64; 3] },
+
+    /// The builder is disconnecting deliberately, e.g. because it is shutting down
+    Goodbye { reason: Option<String> },
 }
 
-impl<'m> TryFrom<&'m str> for SentinelMessage {
-    type Error = serde_json::Error;
+/// Messages sent from the server to a builder
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ServerMessage {
+    /// Accepts a [`ClientMessage::Hello`]
+    Welcome {
+        protocol_version: u32,
+        server_version: String,
+    },
 
-    fn try_from(msg: &'m str) -> Result<Self, Self::Error> {
-        serde_json::from_str(msg)
-    }
+    /// Rejects a [`ClientMessage::Hello`]; the server closes the connection afterwards
+    Reject { reason: String },
+
+    /// Whether the builder should prevent itself from sleeping
+    KeepAwake { awake: bool, reason: Option<String> },
+
+    /// Settings pushed to the builder by the server
+    Config { heartbeat_interval_secs: u64 },
+
+    /// Request that the builder disconnect, e.g. because the server is shutting down
+    Shutdown { reason: Option<String> },
+
+    /// Send a wake-on-lan packet for `mac_address` to `to` on the builder's network, for a builder
+    /// on that network that the server's own packets can't reach. Builders only send to the
+    /// address they're configured to.
+    WakeOnLan {
+        mac_address: [u8; 6],
+        secure_on: Option<Vec<u8>>,
+        to: SocketAddr,
+    },
+
+    /// Suspend or shut down the builder because it's idle, if its client allows it
+    PowerDown {
+        action: PowerAction,
+        reason: Option<String>,
+    },
 }
 
-impl From<SentinelMessage> for String {
-    fn from(val: SentinelMessage) -> Self {
-        serde_json::to_string(&val).expect("to be serializable")
-    }
+/// How an idle builder powers down
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum PowerAction {
+    Suspend,
+    Shutdown,
+}
+
+macro_rules! impl_json_message {
+    ($ty: ty) => {
+        impl<'m> TryFrom<&'m str> for $ty {
+            type Error = serde_json::Error;
+
+            fn try_from(msg: &'m str) -> Result<Self, Self::Error> {
+                serde_json::from_str(msg)
+            }
+        }
+
+        impl From<$ty> for String {
+            fn from(val: $ty) -> Self {
+                serde_json::to_string(&val).expect("to be serializable")
+            }
+        }
+    };
 }
 
+impl_json_message!(ClientMessage);
+impl_json_message!(ServerMessage);
+
 pub fn init<C>(default_directive: &str) -> anyhow::Result<C>
 where
     C: DeserializeOwned,
@@ -84,3 +178,43 @@ pub async fn shutdown_signal() {
         _ = terminate => {},
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_format() {
+        let msg = String::from(ServerMessage::KeepAwake {
+            awake: true,
+            reason: None,
+        });
+        assert_eq!(msg, r#"{"type":"keepAwake","awake":true,"reason":null}"#);
+
+        let msg = String::from(ServerMessage::PowerDown {
+            action: PowerAction::Suspend,
+            reason: None,
+        });
+        assert_eq!(
+            msg,
+            r#"{"type":"powerDown","action":"suspend","reason":null}"#
+        );
+
+        let msg = ClientMessage::try_from(
+            r#"{"type":"hello","protocolVersion":1,"clientVersion":"0.1.0"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::Hello {
+                protocol_version: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn reject_legacy_message() {
+        assert!(ServerMessage::try_from(r#"{"KeepAwake":true}"#).is_err());
+    }
+}
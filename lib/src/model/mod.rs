@@ -0,0 +1,4 @@
+mod build_machine_spec;
+mod system;
+
+pub use self::{build_machine_spec::*, system::*};
@@ -1,6 +1,9 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    Deserialize, Serialize,
+    de::{IntoDeserializer, value::Error},
+};
 
 /// A [Nix system type](https://nixos.org/manual/nix/stable/contributing/hacking#system-type)
 #[derive(Serialize, Deserialize, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug)]
@@ -28,3 +31,11 @@ impl Display for System {
         }
     }
 }
+
+impl FromStr for System {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        System::deserialize(s.into_deserializer())
+    }
+}
@@ -0,0 +1,28 @@
+/// The magic packet waking the machine with `mac_address`: 6 bytes of `0xff` followed by the
+/// address repeated 16 times, then the SecureOn password if any
+pub fn magic_packet(mac_address: &[u8; 6], secure_on: Option<&[u8]>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(6 + 16 * 6 + secure_on.map_or(0, <[u8]>::len));
+    packet.extend_from_slice(&[0xff; 6]);
+    for _ in 0..16 {
+        packet.extend_from_slice(mac_address);
+    }
+    packet.extend_from_slice(secure_on.unwrap_or_default());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet() {
+        let mac_address = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+        let packet = magic_packet(&mac_address, None);
+        assert_eq!(packet.len(), 102);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert!(packet[6..].chunks(6).all(|chunk| chunk == mac_address));
+
+        let packet = magic_packet(&mac_address, Some(&[0x01, 0x23, 0x45, 0x67]));
+        assert_eq!(packet[102..], [0x01, 0x23, 0x45, 0x67]);
+    }
+}
@@ -55,6 +55,8 @@ in
           script = ''
             "${cfg.package}/bin/hydra-sentinel-client" ${toString configFile}
           '';
+          # used to detect systems and features
+          path = [ config.nix.package ];
           serviceConfig = {
             UserName = user.name;
             KeepAlive = true;
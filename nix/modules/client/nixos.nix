@@ -33,6 +33,12 @@ in
       wantedBy = [ "multi-user.target" ];
       bindsTo = [ "network-online.target" ];
       after = [ "network-online.target" ];
+      # used to detect systems and features
+      path = [
+        config.nix.package
+        # used to power down when the server asks to
+        config.systemd.package
+      ];
       serviceConfig =
         let
           confFile = json.generate "config.json" (lib.filterAttrs (_: v: v != null) cfg.settings);
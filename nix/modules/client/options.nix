@@ -24,7 +24,7 @@ in
           type = types.str;
           example = "example.com:3002";
           description = lib.mdDoc ''
-            The address of the Hydra Sentinel server.
+            The address of the Hydra Sentinel server, as `host:port` or a `ws://` or `wss://` URL.
           '';
         };
         hostName = mkOption {
@@ -39,6 +39,39 @@ in
           type = types.str;
           default = "30s";
         };
+        tokenFile = mkOption {
+          type = types.nullOr types.path;
+          default = null;
+          description = lib.mdDoc ''
+            File containing the secret shared with the server, used to sign connection requests.
+          '';
+        };
+        allowPowerDown = mkOption {
+          type = types.bool;
+          default = false;
+          description = lib.mdDoc ''
+            Whether the server may suspend or shut down this machine when it's idle, using
+            `suspendCommand` or `shutdownCommand` if set.
+          '';
+        };
+        wakeOnLan = {
+          broadcastAddress = mkOption {
+            type = types.str;
+            default = "255.255.255.255";
+            example = "192.168.2.255";
+            description = lib.mdDoc ''
+              The only address this machine sends wake-on-lan packets to on behalf of the server,
+              for builders on its network. Must match the woken builder's `wakeOnLan` settings.
+            '';
+          };
+          port = mkOption {
+            type = types.port;
+            default = 9;
+            description = lib.mdDoc ''
+              The only UDP port this machine sends wake-on-lan packets to.
+            '';
+          };
+        };
       };
     };
   };
@@ -55,6 +55,28 @@ in
               '';
             };
 
+            queue = mkOption {
+              type = types.submodule { freeformType = json.type; };
+              default = {
+                source = "hydraApi";
+              };
+              example = {
+                source = "hydraDatabase";
+                connection = "host=/run/postgresql dbname=hydra user=hydra-sentinel-server";
+              };
+              description = mdDoc ''
+                Where to find out which builds are queued: `hydraApi` polls Hydra's JSON API;
+                `hydraDatabase` reads Hydra's database whenever Hydra changes it (the user
+                needs read access to the `builds` and `buildsteps` tables); `file` (with `path`)
+                and `http` (with `url`) poll JSON describing the demand by hand.
+
+                Builds' required system features are read from their derivations in the local
+                Nix store, so they're only taken into account if the server runs on the Hydra
+                host. `hydraApi` fetches each queued build's derivation path from Hydra once, a
+                limited number per poll, and only if builders differ in their features.
+              '';
+            };
+
             hydraMachinesFile = mkOption {
               type = types.path;
               default = "/var/lib/hydra/machines";
@@ -72,6 +94,16 @@ in
               '';
             };
 
+            allowAnyIpWithCertificate = mkOption {
+              type = types.bool;
+              default = false;
+              description = mdDoc ''
+                Whether builders presenting a client certificate issued by `tls.clientCaFile`
+                may connect from IPs not in {option}`allowedIps`. The certificate must still
+                name the host the builder connects as.
+              '';
+            };
+
             heartbeatTimeout = mkOption {
               type = types.str;
               default = "60s";
@@ -92,6 +124,7 @@ in
                   };
                   systems = mkOption {
                     type = types.listOf types.str;
+                    default = [ ];
                     example = [
                       "x86_64-linux"
                       "aarch64-linux"
@@ -101,6 +134,8 @@ in
                       Either this attribute or {var}`system` must be
                       present, where {var}`system` takes precedence if
                       both are set.
+
+                      If empty, the systems reported by the builder's client are used.
                     '';
                   };
                   sshUser = mkOption {
@@ -129,13 +164,15 @@ in
                     '';
                   };
                   maxJobs = mkOption {
-                    type = types.int;
+                    type = types.nullOr types.int;
                     default = 1;
                     description = lib.mdDoc ''
                       The number of concurrent jobs the build machine supports. The
                       build machine will enforce its own limits, but this allows hydra
                       to schedule better since there is no work-stealing between build
                       machines.
+
+                      Set to null to use the CPU count reported by the builder's client instead.
                     '';
                   };
                   speedFactor = mkOption {
@@ -199,6 +236,194 @@ in
                           If present, wake-on-lan will be attempted for this machine when matching jobs are scheduled.
                         '';
                       };
+                      wake = mkOption {
+                        type = types.nullOr (types.submodule { freeformType = json.type; });
+                        default = null;
+                        example = {
+                          via = "http";
+                          url = "http://homeassistant:8123/api/services/switch/turn_on";
+                          body = ''{"entity_id": "switch.nixbuilder"}'';
+                          tokenFile = "/run/secrets/home-assistant-token";
+                        };
+                        description = lib.mdDoc ''
+                          How to wake this machine if not by wake-on-lan to {option}`macAddress`:
+                          `command` (with `command`, a list of arguments), `http` (with `url`,
+                          `method`, `headers`, `body` and `tokenFile`) or `libvirt` (with
+                          `domain` and `uri`).
+                        '';
+                      };
+                      wakeFallback = mkOption {
+                        type = types.nullOr (types.submodule { freeformType = json.type; });
+                        default = null;
+                        example = {
+                          via = "command";
+                          command = [
+                            "ipmitool"
+                            "-H"
+                            "nixbuilder-bmc"
+                            "chassis"
+                            "power"
+                            "on"
+                          ];
+                        };
+                        description = lib.mdDoc ''
+                          Alternative way to wake this machine, like {option}`wake`, used once
+                          waking it failed or it didn't connect after
+                          {option}`wakeRetries.escalateAfter` attempts.
+                        '';
+                      };
+                      wakeVia = mkOption {
+                        type = types.nullOr types.str;
+                        default = null;
+                        description = lib.mdDoc ''
+                          Host name of a builder on the same network to send the wake-on-lan
+                          packet from, if the server's packets can't reach this machine. That
+                          builder's client only sends to its own `wakeOnLan` address and port.
+                        '';
+                      };
+                      wakeOnLan = {
+                        broadcastAddress = mkOption {
+                          type = types.str;
+                          default = "255.255.255.255";
+                          example = "192.168.2.255";
+                          description = lib.mdDoc ''
+                            Address to send the wake-on-lan packet to, e.g. the directed broadcast
+                            address of the builder's subnet if it's on another network.
+                          '';
+                        };
+                        port = mkOption {
+                          type = types.port;
+                          default = 9;
+                          description = lib.mdDoc ''
+                            UDP port to send the wake-on-lan packet to, usually 9 or 7.
+                          '';
+                        };
+                        sourceAddress = mkOption {
+                          type = types.nullOr types.str;
+                          default = null;
+                          description = lib.mdDoc ''
+                            Local address to send the wake-on-lan packet from.
+                          '';
+                        };
+                        interface = mkOption {
+                          type = types.nullOr types.str;
+                          default = null;
+                          example = "eth1";
+                          description = lib.mdDoc ''
+                            Network interface to send the wake-on-lan packet from.
+                          '';
+                        };
+                        secureOn = mkOption {
+                          type = types.nullOr types.str;
+                          default = null;
+                          example = "01:23:45:67";
+                          description = lib.mdDoc ''
+                            SecureOn password expected by the builder's network card.
+                          '';
+                        };
+                      };
+                      tokenFile = mkOption {
+                        type = types.nullOr types.path;
+                        default = null;
+                        description = lib.mdDoc ''
+                          File containing a secret shared with this builder's client. If set,
+                          the builder must sign its connection requests with it.
+                        '';
+                      };
+                      policy = {
+                        priority = mkOption {
+                          type = types.int;
+                          default = 0;
+                          description = lib.mdDoc ''
+                            Builders with a higher priority are woken first.
+                          '';
+                        };
+                        lastResort = mkOption {
+                          type = types.bool;
+                          default = false;
+                          description = lib.mdDoc ''
+                            Only wake this builder for builds that no other available builder can run.
+                          '';
+                        };
+                        wakeHours = mkOption {
+                          type = types.nullOr (
+                            types.submodule {
+                              options = {
+                                from = mkOption {
+                                  type = types.str;
+                                  example = "07:00";
+                                };
+                                to = mkOption {
+                                  type = types.str;
+                                  example = "22:00";
+                                };
+                              };
+                            }
+                          );
+                          default = null;
+                          description = lib.mdDoc ''
+                            Local time window in which this builder may be woken. Wraps around
+                            midnight if `to` is before `from`.
+                          '';
+                        };
+                        minAwake = mkOption {
+                          type = types.nullOr types.str;
+                          default = null;
+                          example = "15m";
+                          description = lib.mdDoc ''
+                            Keep this builder awake for at least this long after it connects.
+                          '';
+                        };
+                        maxAwakePerDay = mkOption {
+                          type = types.nullOr types.str;
+                          default = null;
+                          example = "4h";
+                          description = lib.mdDoc ''
+                            Neither wake this builder nor keep it awake once it's been connected
+                            this long today.
+                          '';
+                        };
+                        idleGracePeriod = mkOption {
+                          type = types.nullOr types.str;
+                          default = null;
+                          example = "15m";
+                          description = lib.mdDoc ''
+                            Overrides the global {option}`keepAwake.idleGracePeriod`.
+                          '';
+                        };
+                        minAwakeAfterWake = mkOption {
+                          type = types.nullOr types.str;
+                          default = null;
+                          example = "30m";
+                          description = lib.mdDoc ''
+                            Overrides the global {option}`keepAwake.minAwakeAfterWake`.
+                          '';
+                        };
+                        powerDown = mkOption {
+                          type = types.nullOr (
+                            types.submodule {
+                              options = {
+                                action = mkOption {
+                                  type = types.enum [
+                                    "suspend"
+                                    "shutdown"
+                                  ];
+                                  default = "suspend";
+                                };
+                                after = mkOption {
+                                  type = types.str;
+                                  example = "10m";
+                                };
+                              };
+                            }
+                          );
+                          default = null;
+                          description = lib.mdDoc ''
+                            Ask this builder to suspend or shut down once it hasn't been kept awake
+                            for `after`. Its client must set `allowPowerDown`.
+                          '';
+                        };
+                      };
                     };
                   }
                 );
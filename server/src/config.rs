@@ -1,7 +1,7 @@
 use crate::model::BuildMachine;
 use ipnet::IpNet;
 use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{collections::HashSet, net::SocketAddr, path::PathBuf, time::Duration};
 use url::Url;
 
 #[derive(Deserialize, Debug)]
@@ -10,6 +10,10 @@ pub struct Config {
     /// Base URL of the Hydra server
     pub hydra_base_url: Url,
 
+    /// How to authenticate with Hydra, if it requires login for the endpoints used
+    #[serde(default)]
+    pub hydra_auth: HydraAuth,
+
     /// Path to the dynamically generated machines spec managed by sentinel
     /// Must be writable
     pub hydra_machines_file: PathBuf,
@@ -17,19 +21,335 @@ pub struct Config {
     /// Address + port to listen on
     pub listen_addr: SocketAddr,
 
-    /// GitHub webhook secret for authenticating push events
-    pub github_webhook_secret_file: PathBuf,
+    /// Serve over TLS (https:// and wss://) instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+
+    /// GitHub webhook secret for authenticating push events. The `/webhook` endpoint is only
+    /// served if set.
+    #[serde(default)]
+    pub github_webhook_secret_file: Option<PathBuf>,
+
+    /// Which GitHub webhook events trigger a Hydra evaluation
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+
+    /// GitLab webhook secret token, enables `/webhook/gitlab`
+    #[serde(default)]
+    pub gitlab_webhook_token_file: Option<PathBuf>,
+
+    /// Gitea or Forgejo webhook secret, enables `/webhook/gitea`
+    #[serde(default)]
+    pub gitea_webhook_secret_file: Option<PathBuf>,
+
+    /// Webhook for any sender that signs its requests, enables `/webhook/generic`
+    #[serde(default)]
+    pub generic_webhook: Option<GenericWebhookConfig>,
 
     /// Whitelisted builder IPs
     #[serde(default)]
     pub allowed_ips: Vec<IpNet>,
 
+    /// Let builders presenting a client certificate verified against `tls.clientCaFile` connect
+    /// from IPs not in `allowedIps`
+    #[serde(default)]
+    pub allow_any_ip_with_certificate: bool,
+
     /// Time after not hearing from a builder that it is considered dead
     #[serde(with = "humantime_serde")]
     pub heartbeat_timeout: Duration,
 
     /// List of known machine specs
-    /// TODO: What about dynamically registered machines?
     #[serde(default)]
     pub build_machines: Vec<BuildMachine>,
+
+    /// Where to find out which builds are queued
+    #[serde(default)]
+    pub queue: QueueConfig,
+
+    /// How often to poll the queue and re-evaluate decisions
+    #[serde(default)]
+    pub intervals: IntervalConfig,
+
+    /// Whether builders not listed in `buildMachines` may register their own spec
+    #[serde(default)]
+    pub registration: RegistrationPolicy,
+
+    /// How long builders are kept awake when there's nothing for them to build. Can be
+    /// overridden per builder in its `policy`.
+    #[serde(default)]
+    pub keep_awake: KeepAwakeConfig,
+
+    /// How often to retry waking builders that don't connect, and when to give up
+    #[serde(default)]
+    pub wake_retries: WakeRetryConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct IntervalConfig {
+    /// How often to poll the queue
+    #[serde(with = "humantime_serde")]
+    pub poll: Duration,
+
+    /// If set, poll at this (typically longer) interval instead while nothing is queued, running
+    /// or waking up
+    #[serde(with = "humantime_serde")]
+    pub idle_poll: Option<Duration>,
+
+    /// Upper bound for the exponential backoff between polls after errors
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+
+    /// How often to re-evaluate which builders to wake or keep awake, and regenerate the
+    /// machines file, even if nothing changed
+    #[serde(with = "humantime_serde")]
+    pub reevaluate: Duration,
+}
+
+impl Default for IntervalConfig {
+    fn default() -> Self {
+        IntervalConfig {
+            poll: Duration::from_secs(15),
+            idle_poll: None,
+            max_backoff: Duration::from_secs(5 * 60),
+            reevaluate: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct KeepAwakeConfig {
+    /// Keep builders awake for this long after the queue empties, so they don't go to sleep
+    /// between closely spaced evaluations
+    #[serde(with = "humantime_serde")]
+    pub idle_grace_period: Duration,
+
+    /// Keep builders awake for at least this long after waking them with wake-on-lan
+    #[serde(with = "humantime_serde")]
+    pub min_awake_after_wake: Duration,
+}
+
+impl Default for KeepAwakeConfig {
+    fn default() -> Self {
+        KeepAwakeConfig {
+            idle_grace_period: Duration::from_secs(5 * 60),
+            min_awake_after_wake: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WakeRetryConfig {
+    /// Wait this long for a builder to connect before trying to wake it again. Doubles with every
+    /// attempt, and is at least as long as the builder took to connect the last time.
+    #[serde(with = "humantime_serde")]
+    pub retry_interval: Duration,
+
+    /// Upper bound for the time between attempts
+    #[serde(with = "humantime_serde")]
+    pub max_retry_interval: Duration,
+
+    /// Use the builder's `wakeFallback` after this many attempts, or once an attempt fails
+    pub escalate_after: u32,
+
+    /// Consider builders unreachable and stop waking them after this many attempts, until they
+    /// connect by themselves. Never give up if null.
+    pub give_up_after: Option<u32>,
+
+    /// Consider an attempt failed if waking, e.g. running a command or sending a request, takes
+    /// longer than this
+    #[serde(with = "humantime_serde")]
+    pub attempt_timeout: Duration,
+}
+
+impl Default for WakeRetryConfig {
+    fn default() -> Self {
+        WakeRetryConfig {
+            retry_interval: Duration::from_secs(60),
+            max_retry_interval: Duration::from_secs(15 * 60),
+            escalate_after: 2,
+            give_up_after: Some(5),
+            attempt_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    /// PEM-encoded certificate chain. Reloaded when changed.
+    pub cert_file: PathBuf,
+
+    /// PEM-encoded private key. Reloaded when changed.
+    pub key_file: PathBuf,
+
+    /// PEM bundle of CAs that issue builder client certificates. If set, builders must present a
+    /// certificate whose DNS name (or common name) matches the host name they connect as.
+    pub client_ca_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(
+    tag = "source",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum QueueConfig {
+    /// Poll Hydra's JSON API. The queue doesn't include derivations, so each build's is fetched
+    /// from `/build/<id>` once, if builders differ in their features.
+    HydraApi {
+        /// Only fetch this many of the highest priority queued builds, to keep responses small
+        /// on busy instances. If the queue is longer, every kind of build seen before is
+        /// assumed to be among the rest.
+        #[serde(default)]
+        max_builds: Option<u32>,
+    },
+
+    /// Read the queue from Hydra's database whenever Hydra changes it
+    HydraDatabase {
+        /// PostgreSQL connection string, e.g.
+        /// `host=/run/postgresql dbname=hydra user=hydra-sentinel-server`
+        connection: String,
+    },
+
+    /// Poll a JSON file describing the demand by hand
+    File { path: PathBuf },
+
+    /// Poll a URL serving JSON describing the demand by hand
+    Http { url: Url },
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig::HydraApi { max_builds: None }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WebhookConfig {
+    /// Event types forwarded to Hydra. `ping` is always acknowledged.
+    pub events: HashSet<GitHubEvent>,
+
+    /// If any are given, only events matching one of these rules are forwarded
+    pub rules: Vec<WebhookRule>,
+
+    /// If set, forwarded events wake builders for the kinds of builds the project had before, and
+    /// keep them awake this long, so they are up by the time the evaluation queues builds. Also
+    /// applies to the GitLab, Gitea and generic webhooks, whose projects are assumed to be named
+    /// like the repository, or taken from the triggered jobsets.
+    #[serde(with = "humantime_serde")]
+    pub expect_builds_for: Option<Duration>,
+
+    /// Number of recent delivery IDs remembered, to reject replayed deliveries
+    pub remember_deliveries: usize,
+
+    /// File to persist recent delivery IDs in, so replays are rejected after a restart too
+    pub deliveries_file: Option<PathBuf>,
+
+    /// If set, events for the same repository within this window are collapsed into a single
+    /// Hydra trigger with the latest event, sent once the window has passed
+    #[serde(with = "humantime_serde")]
+    pub debounce: Option<Duration>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            events: HashSet::from([GitHubEvent::Push]),
+            rules: vec![],
+            expect_builds_for: None,
+            remember_deliveries: 1000,
+            deliveries_file: None,
+            debounce: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GenericWebhookConfig {
+    /// Secret the request body is signed with, as a hex encoded HMAC-SHA256 in the
+    /// `X-Hub-Signature-256` header, optionally prefixed with `sha256=`
+    pub secret_file: PathBuf,
+
+    /// Jobsets to evaluate on each request, as `project:jobset`
+    #[serde(default)]
+    pub jobsets: Vec<String>,
+
+    /// Evaluate jobsets with any of these repository URLs as an input
+    #[serde(default)]
+    pub repos: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum GitHubEvent {
+    Push,
+    PullRequest,
+    Create,
+    Release,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookRule {
+    /// Full name of the repository, e.g. `NixOS/nixpkgs`
+    pub repository: String,
+
+    /// Branches the rule applies to; a trailing `*` matches any suffix. All branches if empty.
+    #[serde(default)]
+    pub branches: Vec<String>,
+
+    /// Event types the rule applies to. All forwarded event types if empty.
+    #[serde(default)]
+    pub events: HashSet<GitHubEvent>,
+
+    /// Hydra project the repository is built by, if not named like the repository
+    #[serde(default)]
+    pub project: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(
+    tag = "method",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum HydraAuth {
+    #[default]
+    None,
+
+    /// Log in with the `username:password` in `credentials_file`, keeping the session cookie and
+    /// logging in again when it expires
+    Login { credentials_file: PathBuf },
+
+    /// Send the token in `token_file` as a bearer token, e.g. to a proxy in front of Hydra
+    Bearer { token_file: PathBuf },
+
+    /// Send the `username:password` in `credentials_file` using HTTP basic authentication
+    Basic { credentials_file: PathBuf },
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(
+    tag = "mode",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum RegistrationPolicy {
+    /// Only builders listed in `buildMachines` may connect
+    #[default]
+    Disabled,
+
+    /// Builders with one of the listed host names may register
+    AllowList { host_names: HashSet<String> },
+
+    /// Builders presenting the pre-shared token may register
+    Token { token_file: PathBuf },
+
+    /// Any builder may register
+    Open,
 }
@@ -0,0 +1,2 @@
+//! Webhooks from forges other than GitHub
+pub mod webhook;
@@ -0,0 +1,235 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::GenericWebhookConfig;
+use crate::error::AppError;
+use crate::github::middleware::{Signature, validate_request_signature, validate_token};
+use crate::hydra::client::HydraClient;
+use crate::hydra::store::Store;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware;
+use axum::routing::{MethodRouter, post};
+use secrecy::SecretString;
+use serde::Deserialize;
+
+#[derive(Clone)]
+pub struct Forge {
+    client: HydraClient,
+    store: Arc<Store>,
+    /// How long to expect builds for after forwarding an event, as for GitHub
+    expect_builds_for: Option<Duration>,
+}
+
+impl Forge {
+    pub fn new(
+        client: HydraClient,
+        store: Arc<Store>,
+        expect_builds_for: Option<Duration>,
+    ) -> Self {
+        Self {
+            client,
+            store,
+            expect_builds_for,
+        }
+    }
+
+    /// Wake builders for the kinds of builds `project` had before, if configured
+    fn expect_builds(&self, project: &str) {
+        if let Some(expect_builds_for) = self.expect_builds_for {
+            let expected = self
+                .store
+                .expect_builds(project, Instant::now() + expect_builds_for);
+            tracing::info!(?expected, "Expecting builds for {project}");
+        }
+    }
+}
+
+/// Last component of a repository's full name, which projects are assumed to be named like
+pub(crate) fn repository_name(full_name: &str) -> &str {
+    full_name
+        .rsplit_once('/')
+        .map_or(full_name, |(_, name)| name)
+}
+
+fn event<'a>(headers: &'a HeaderMap, header: &str) -> Result<&'a str, AppError> {
+    headers
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| {
+            AppError::from((StatusCode::BAD_REQUEST, format!("Missing {header} header")))
+        })
+}
+
+/// Full name and repository URLs of a GitLab push. Hydra matches the URLs against jobset inputs.
+fn gitlab_push(payload: &str) -> serde_json::Result<(String, Vec<String>)> {
+    #[derive(Deserialize)]
+    struct Project {
+        path_with_namespace: String,
+        git_http_url: String,
+        git_ssh_url: String,
+    }
+    #[derive(Deserialize)]
+    struct Payload {
+        project: Project,
+    }
+
+    let payload = serde_json::from_str::<Payload>(payload)?;
+    Ok((
+        payload.project.path_with_namespace,
+        vec![payload.project.git_http_url, payload.project.git_ssh_url],
+    ))
+}
+
+/// Full name of the repository of a Gitea push
+fn gitea_repository(payload: &str) -> serde_json::Result<String> {
+    #[derive(Deserialize)]
+    struct Repository {
+        full_name: String,
+    }
+    #[derive(Deserialize)]
+    struct Payload {
+        repository: Repository,
+    }
+
+    Ok(serde_json::from_str::<Payload>(payload)?
+        .repository
+        .full_name)
+}
+
+/// Projects of the `project:jobset`s triggered by the generic webhook
+fn jobset_projects(jobsets: &[String]) -> Vec<&str> {
+    let mut projects = jobsets
+        .iter()
+        .filter_map(|jobset| Some(jobset.split_once(':')?.0))
+        .collect::<Vec<_>>();
+    projects.sort_unstable();
+    projects.dedup();
+    projects
+}
+
+#[tracing::instrument(skip_all, err)]
+async fn gitlab(
+    State(forge): State<Forge>,
+    headers: HeaderMap,
+    payload: String,
+) -> Result<&'static str, AppError> {
+    let kind = event(&headers, "X-Gitlab-Event")?;
+    if !matches!(kind, "Push Hook" | "Tag Push Hook") {
+        tracing::debug!("Ignoring GitLab {kind}");
+        return Ok("Ignored event type");
+    }
+
+    let (repository, repos) = gitlab_push(&payload)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid payload: {err}")))?;
+    forge.expect_builds(repository_name(&repository));
+    tracing::info!(?repos, "Forwarding GitLab push to Hydra");
+    let response = forge.client.push_jobsets(&[], &repos).await?;
+    tracing::info!(?response);
+    Ok("Forwarded to Hydra")
+}
+
+#[tracing::instrument(skip_all, err)]
+async fn gitea(
+    State(forge): State<Forge>,
+    headers: HeaderMap,
+    payload: String,
+) -> Result<&'static str, AppError> {
+    let kind = event(&headers, "X-Gitea-Event")?;
+    if kind != "push" {
+        tracing::debug!("Ignoring Gitea {kind} event");
+        return Ok("Ignored event type");
+    }
+
+    let repository = gitea_repository(&payload)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid payload: {err}")))?;
+    forge.expect_builds(repository_name(&repository));
+    tracing::info!("Forwarding Gitea push to Hydra");
+    tracing::trace!(?payload);
+    let response = forge.client.push_gitea(payload).await?;
+    tracing::info!(?response);
+    Ok("Forwarded to Hydra")
+}
+
+#[tracing::instrument(skip_all, err)]
+async fn generic(
+    State((forge, config)): State<(Forge, Arc<GenericWebhookConfig>)>,
+) -> Result<&'static str, AppError> {
+    // projects of jobsets triggered through `repos` aren't known
+    for project in jobset_projects(&config.jobsets) {
+        forge.expect_builds(project);
+    }
+    tracing::info!(?config.jobsets, ?config.repos, "Triggering Hydra evaluation");
+    let response = forge
+        .client
+        .push_jobsets(&config.jobsets, &config.repos)
+        .await?;
+    tracing::info!(?response);
+    Ok("Forwarded to Hydra")
+}
+
+pub fn gitlab_handler(token: SecretString) -> MethodRouter<Forge, Infallible> {
+    post(gitlab).route_layer(middleware::from_fn_with_state(
+        ("X-Gitlab-Token", token),
+        validate_token,
+    ))
+}
+
+pub fn gitea_handler(secret: SecretString) -> MethodRouter<Forge, Infallible> {
+    post(gitea).route_layer(middleware::from_fn_with_state(
+        Signature::gitea(secret),
+        validate_request_signature,
+    ))
+}
+
+pub fn generic_handler(
+    secret: SecretString,
+) -> MethodRouter<(Forge, Arc<GenericWebhookConfig>), Infallible> {
+    post(generic).route_layer(middleware::from_fn_with_state(
+        Signature::generic(secret),
+        validate_request_signature,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gitlab_push_event() {
+        let payload = r#"{
+            "object_kind": "push",
+            "project": {
+                "path_with_namespace": "example/project",
+                "git_http_url": "https://gitlab.example.org/example/project.git",
+                "git_ssh_url": "git@gitlab.example.org:example/project.git"
+            }
+        }"#;
+        let (repository, repos) = gitlab_push(payload).unwrap();
+        assert_eq!(repository_name(&repository), "project");
+        assert_eq!(
+            repos,
+            [
+                "https://gitlab.example.org/example/project.git",
+                "git@gitlab.example.org:example/project.git"
+            ]
+        );
+    }
+
+    #[test]
+    fn gitea_push_event() {
+        let payload = r#"{
+            "ref": "refs/heads/main",
+            "repository": { "name": "nix-config", "full_name": "example/nix-config" }
+        }"#;
+        assert_eq!(gitea_repository(payload).unwrap(), "example/nix-config");
+    }
+
+    #[test]
+    fn generic_projects() {
+        let jobsets =
+            ["nix-config:main", "nix-config:next", "other:main", "bogus"].map(String::from);
+        assert_eq!(jobset_projects(&jobsets), ["nix-config", "other"]);
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::Context;
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// Recently seen webhook delivery IDs, to reject replayed deliveries. Only the most recent
+/// `capacity` are remembered, optionally persisted to a file to survive restarts.
+pub struct Deliveries {
+    capacity: usize,
+    file: Option<PathBuf>,
+    seen: Mutex<Seen>,
+    /// Held while persisting, so that writes don't interleave and the last one wins
+    writing: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl Seen {
+    fn insert(&mut self, id: &str, capacity: usize) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        while self.order.len() >= capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.ids.remove(&oldest);
+        }
+        self.order.push_back(id.to_string());
+        self.ids.insert(id.to_string());
+        true
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        if !self.ids.remove(id) {
+            return false;
+        }
+        self.order.retain(|seen| seen != id);
+        true
+    }
+}
+
+impl Deliveries {
+    pub fn load(capacity: usize, file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let mut seen = Seen::default();
+        if let Some(path) = &file {
+            match std::fs::read_to_string(path) {
+                Ok(ids) => {
+                    for id in ids.lines().filter(|id| !id.is_empty()) {
+                        seen.insert(id, capacity);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to read deliveries {path:?}"));
+                }
+            }
+        }
+        Ok(Self {
+            capacity,
+            file,
+            seen: Mutex::new(seen),
+            writing: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Record a delivery, returning whether it is new
+    pub async fn insert(&self, id: &str) -> bool {
+        if !self.seen.lock().unwrap().insert(id, self.capacity) {
+            return false;
+        }
+        self.persist().await;
+        true
+    }
+
+    /// Forget a delivery that failed, so that it can be redelivered
+    pub async fn remove(&self, id: &str) {
+        if self.seen.lock().unwrap().remove(id) {
+            self.persist().await;
+        }
+    }
+
+    /// Replace the file with the current deliveries, through a temporary file so that it is
+    /// never left half-written
+    async fn persist(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let _writing = self.writing.lock().await;
+        let ids = self
+            .seen
+            .lock()
+            .unwrap()
+            .order
+            .iter()
+            .flat_map(|id| [id.as_str(), "\n"])
+            .collect::<String>();
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let result = async {
+            tokio::fs::write(&temp, ids).await?;
+            tokio::fs::rename(&temp, path).await
+        };
+        if let Err(err) = result.await {
+            tracing::warn!(?err, "Failed to persist deliveries to {path:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reject_replays() {
+        let deliveries = Deliveries::load(2, None).unwrap();
+        assert!(deliveries.insert("a").await);
+        assert!(!deliveries.insert("a").await);
+        assert!(deliveries.insert("b").await);
+        assert!(deliveries.insert("c").await);
+        // only the most recent deliveries are remembered
+        assert!(deliveries.insert("a").await);
+        assert!(!deliveries.insert("c").await);
+    }
+
+    #[tokio::test]
+    async fn persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("deliveries");
+        let deliveries = Deliveries::load(10, Some(file.clone())).unwrap();
+        assert!(deliveries.insert("a").await);
+        assert!(deliveries.insert("b").await);
+        deliveries.remove("a").await;
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "b\n");
+
+        let deliveries = Deliveries::load(10, Some(file)).unwrap();
+        assert!(deliveries.insert("a").await);
+        assert!(!deliveries.insert("b").await);
+    }
+}
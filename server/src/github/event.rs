@@ -0,0 +1,196 @@
+use serde::Deserialize;
+use std::str::FromStr;
+
+use crate::{
+    config::{GitHubEvent, WebhookConfig, WebhookRule},
+    forge::webhook::repository_name,
+};
+
+impl FromStr for GitHubEvent {
+    type Err = ();
+
+    /// Parse the `X-GitHub-Event` header
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "push" => Ok(GitHubEvent::Push),
+            "pull_request" => Ok(GitHubEvent::PullRequest),
+            "create" => Ok(GitHubEvent::Create),
+            "release" => Ok(GitHubEvent::Release),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The parts of an event's payload that rules match against
+#[derive(Debug)]
+pub struct Event {
+    pub kind: GitHubEvent,
+    pub repository: String,
+    /// Branch the event concerns, if any, e.g. not for tag pushes
+    pub branch: Option<String>,
+}
+
+impl Event {
+    pub fn parse(kind: GitHubEvent, payload: &str) -> serde_json::Result<Self> {
+        #[derive(Deserialize)]
+        struct Repository {
+            full_name: String,
+        }
+        #[derive(Deserialize)]
+        struct Base {
+            #[serde(rename = "ref")]
+            branch: String,
+        }
+        #[derive(Deserialize)]
+        struct PullRequest {
+            base: Base,
+        }
+        #[derive(Deserialize)]
+        struct Release {
+            target_commitish: String,
+        }
+        #[derive(Deserialize)]
+        struct Payload {
+            repository: Repository,
+            #[serde(rename = "ref")]
+            git_ref: Option<String>,
+            ref_type: Option<String>,
+            pull_request: Option<PullRequest>,
+            release: Option<Release>,
+        }
+
+        let payload = serde_json::from_str::<Payload>(payload)?;
+        let branch = match kind {
+            GitHubEvent::Push => payload
+                .git_ref
+                .and_then(|git_ref| git_ref.strip_prefix("refs/heads/").map(str::to_string)),
+            GitHubEvent::PullRequest => payload.pull_request.map(|pr| pr.base.branch),
+            GitHubEvent::Create => payload
+                .git_ref
+                .filter(|_| payload.ref_type.as_deref() == Some("branch")),
+            GitHubEvent::Release => payload.release.map(|release| release.target_commitish),
+        };
+        Ok(Event {
+            kind,
+            repository: payload.repository.full_name,
+            branch,
+        })
+    }
+}
+
+impl WebhookRule {
+    fn matches(&self, event: &Event) -> bool {
+        let branch_matches = |pattern: &String| {
+            let Some(branch) = &event.branch else {
+                return false;
+            };
+            match pattern.strip_suffix('*') {
+                Some(prefix) => branch.starts_with(prefix),
+                None => branch == pattern,
+            }
+        };
+        self.repository.eq_ignore_ascii_case(&event.repository)
+            && (self.events.is_empty() || self.events.contains(&event.kind))
+            && (self.branches.is_empty() || self.branches.iter().any(branch_matches))
+    }
+}
+
+impl WebhookConfig {
+    /// Whether the event should trigger a Hydra evaluation
+    pub fn forwards(&self, event: &Event) -> bool {
+        self.events.contains(&event.kind)
+            && (self.rules.is_empty() || self.rules.iter().any(|rule| rule.matches(event)))
+    }
+
+    /// Hydra project the event's repository is built by
+    pub fn project<'a>(&'a self, event: &'a Event) -> &'a str {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(event))
+            .find_map(|rule| rule.project.as_deref())
+            .unwrap_or_else(|| repository_name(&event.repository))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    const REPOSITORY: &str = r#""repository": {"full_name": "NixOS/nixpkgs"}"#;
+
+    fn event(kind: GitHubEvent, payload: &str) -> Event {
+        Event::parse(kind, &format!("{{{REPOSITORY}, {payload}}}")).unwrap()
+    }
+
+    #[test]
+    fn parse_branch() {
+        let branch = |kind, payload| event(kind, payload).branch;
+        assert_eq!(
+            branch(GitHubEvent::Push, r#""ref": "refs/heads/master""#).as_deref(),
+            Some("master")
+        );
+        assert_eq!(branch(GitHubEvent::Push, r#""ref": "refs/tags/v1""#), None);
+        assert_eq!(
+            branch(
+                GitHubEvent::PullRequest,
+                r#""pull_request": {"base": {"ref": "staging"}}"#
+            )
+            .as_deref(),
+            Some("staging")
+        );
+        assert_eq!(
+            branch(GitHubEvent::Create, r#""ref": "v1", "ref_type": "tag""#),
+            None
+        );
+        assert_eq!(
+            branch(
+                GitHubEvent::Release,
+                r#""release": {"target_commitish": "release-24.05"}"#
+            )
+            .as_deref(),
+            Some("release-24.05")
+        );
+    }
+
+    #[test]
+    fn forward_rules() {
+        let mut config = WebhookConfig::default();
+        let push = |branch| {
+            event(
+                GitHubEvent::Push,
+                &format!(r#""ref": "refs/heads/{branch}""#),
+            )
+        };
+        let pull_request = event(
+            GitHubEvent::PullRequest,
+            r#""pull_request": {"base": {"ref": "master"}}"#,
+        );
+        assert!(config.forwards(&push("master")));
+        assert!(!config.forwards(&pull_request));
+
+        config.events.insert(GitHubEvent::PullRequest);
+        config.rules = vec![
+            WebhookRule {
+                repository: "nixos/nixpkgs".to_string(),
+                branches: vec!["master".to_string(), "release-*".to_string()],
+                events: HashSet::from([GitHubEvent::Push]),
+                project: Some("nixos".to_string()),
+            },
+            WebhookRule {
+                repository: "NixOS/hydra".to_string(),
+                branches: vec![],
+                events: HashSet::new(),
+                project: None,
+            },
+        ];
+        assert!(config.forwards(&push("master")));
+        assert!(config.forwards(&push("release-24.05")));
+        assert!(!config.forwards(&push("staging")));
+        // the rule only applies to pushes
+        assert!(!config.forwards(&pull_request));
+
+        assert_eq!(config.project(&push("master")), "nixos");
+        assert_eq!(config.project(&pull_request), "nixpkgs");
+    }
+}
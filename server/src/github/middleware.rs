@@ -8,30 +8,92 @@ use axum::{
 use hmac::{Hmac, Mac, digest::MacError};
 use secrecy::{ExposeSecret, SecretString};
 use sha2::Sha256;
+use subtle::ConstantTimeEq;
 
 use crate::error::AppError;
 
+/// How a webhook sender signs request bodies with HMAC-SHA256
+#[derive(Clone)]
+pub struct Signature {
+    header: &'static str,
+    /// Prefix of the hex encoded signature, required if `strict`
+    prefix: &'static str,
+    strict: bool,
+    secret: SecretString,
+}
+
+impl Signature {
+    pub fn github(secret: SecretString) -> Self {
+        Self {
+            header: "X-Hub-Signature-256",
+            prefix: "sha256=",
+            strict: true,
+            secret,
+        }
+    }
+
+    pub fn gitea(secret: SecretString) -> Self {
+        Self {
+            header: "X-Gitea-Signature",
+            prefix: "",
+            strict: true,
+            secret,
+        }
+    }
+
+    /// GitHub style signatures, with or without the `sha256=` prefix
+    pub fn generic(secret: SecretString) -> Self {
+        Self {
+            strict: false,
+            ..Self::github(secret)
+        }
+    }
+}
+
 pub async fn validate_request_signature(
-    State(secret): State<SecretString>,
+    State(signature): State<Signature>,
+    request: Request<Body>,
+    next: middleware::Next,
+) -> Result<impl IntoResponse, AppError> {
+    let request = do_validate_signature(&signature, request).await?;
+    Ok(next.run(request).await)
+}
+
+/// Check a pre-shared token sent as is, as GitLab does
+pub async fn validate_token(
+    State((header, token)): State<(&'static str, SecretString)>,
     request: Request<Body>,
     next: middleware::Next,
 ) -> Result<impl IntoResponse, AppError> {
-    let request = do_validate_signature(&secret, request).await?;
+    let presented = request.headers().get(header).ok_or_else(|| {
+        AppError::from((StatusCode::UNAUTHORIZED, format!("Missing {header} header")))
+    })?;
+    if !bool::from(presented.as_bytes().ct_eq(token.expose_secret().as_bytes())) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid token").into());
+    }
     Ok(next.run(request).await)
 }
 
-fn extract_signature(headers: &HeaderMap) -> Result<Vec<u8>, AppError> {
+fn extract_signature(signature: &Signature, headers: &HeaderMap) -> Result<Vec<u8>, AppError> {
     let header = headers
-        .get("X-Hub-Signature-256")
+        .get(signature.header)
         .and_then(|value| value.to_str().ok())
-        .ok_or((
-            StatusCode::BAD_REQUEST,
-            "Missing X-Hub-Signature-256 header",
-        ))?;
-    let hex = header.strip_prefix("sha256=").ok_or((
-        StatusCode::BAD_REQUEST,
-        "Invalid signature format, expected sha256=...",
-    ))?;
+        .ok_or_else(|| {
+            AppError::from((
+                StatusCode::BAD_REQUEST,
+                format!("Missing {} header", signature.header),
+            ))
+        })?;
+    let hex = match header.strip_prefix(signature.prefix) {
+        Some(hex) => hex,
+        None if !signature.strict => header,
+        None => {
+            return Err(AppError::from((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid signature format, expected {}...", signature.prefix),
+            )));
+        }
+    };
     Ok(hex::decode(hex).map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid hex: {err}")))?)
 }
 
@@ -49,16 +111,16 @@ fn validate_signature(
 // the trick is to take the request apart, buffer the body, do what you need to do, then put
 // the request back together
 async fn do_validate_signature(
-    secret: &SecretString,
+    signature: &Signature,
     request: Request<Body>,
 ) -> Result<Request<Body>, AppError> {
     let (parts, body) = request.into_parts();
-    let signature = extract_signature(&parts.headers)?;
+    let expected = extract_signature(signature, &parts.headers)?;
     let body = body::to_bytes(body, 1024 * 1024)
         .await
         .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
 
-    if validate_signature(secret, &body, &signature).is_ok() {
+    if validate_signature(&signature.secret, &body, &expected).is_ok() {
         Ok(Request::from_parts(parts, Body::from(body)))
     } else {
         Err((StatusCode::BAD_REQUEST, "Invalid signature").into())
@@ -78,12 +140,11 @@ mod tests {
         "ok"
     }
 
-    #[tokio::test]
-    async fn valid_signature() {
+    async fn send(signature: Signature, header: &str, value: &str) -> StatusCode {
         let app = Router::new().route(
             "/",
             post(ok).layer(middleware::from_fn_with_state(
-                SecretString::from("It's a Secret to Everybody"),
+                signature,
                 super::validate_request_signature,
             )),
         );
@@ -92,16 +153,51 @@ mod tests {
             .oneshot(
                 Request::builder()
                     .method(Method::POST)
-                    .header(
-                        "X-Hub-Signature-256",
-                        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
-                    )
+                    .header(header, value)
                     .uri("/")
                     .body(Body::from("Hello, World!"))
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
+        res.status()
+    }
+
+    #[tokio::test]
+    async fn valid_signature() {
+        let secret = || SecretString::from("It's a Secret to Everybody");
+        let hex = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        let prefixed = format!("sha256={hex}");
+
+        assert_eq!(
+            send(
+                Signature::github(secret()),
+                "X-Hub-Signature-256",
+                &prefixed
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(Signature::github(secret()), "X-Hub-Signature-256", hex).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            send(Signature::gitea(secret()), "X-Gitea-Signature", hex).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(Signature::generic(secret()), "X-Hub-Signature-256", hex).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(
+                Signature::generic(secret()),
+                "X-Hub-Signature-256",
+                &prefixed
+            )
+            .await,
+            StatusCode::OK
+        );
     }
 }
@@ -1,2 +1,4 @@
-mod middleware;
+mod deliveries;
+mod event;
+pub mod middleware;
 pub mod webhook;
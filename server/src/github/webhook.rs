@@ -1,25 +1,201 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use super::middleware::validate_request_signature;
+use super::deliveries::Deliveries;
+use super::event::Event;
+use super::middleware::{Signature, validate_request_signature};
+use crate::config::{GitHubEvent, WebhookConfig};
 use crate::error::AppError;
 use crate::hydra::client::HydraClient;
+use crate::hydra::store::Store;
 use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
 use axum::middleware;
 use axum::routing::post;
 use secrecy::SecretString;
 
+#[derive(Clone)]
+pub struct Webhook {
+    client: HydraClient,
+    config: Arc<WebhookConfig>,
+    store: Arc<Store>,
+    deliveries: Arc<Deliveries>,
+    /// Trigger of each repository waiting out the debounce window
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+/// Latest payload of a repository, and the deliveries collapsed into it
+struct Pending {
+    payload: String,
+    deliveries: Vec<String>,
+}
+
+impl Webhook {
+    pub fn new(
+        client: HydraClient,
+        config: WebhookConfig,
+        store: Arc<Store>,
+    ) -> anyhow::Result<Self> {
+        let deliveries =
+            Deliveries::load(config.remember_deliveries, config.deliveries_file.clone())?;
+        Ok(Self {
+            client,
+            config: Arc::new(config),
+            store,
+            deliveries: Arc::new(deliveries),
+            pending: Default::default(),
+        })
+    }
+
+    /// Forward the latest payload for `repository` once the debounce `window` has passed
+    fn debounce(
+        &self,
+        window: Duration,
+        repository: String,
+        delivery: &str,
+        payload: String,
+    ) -> &'static str {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(pending) = pending.get_mut(&repository) {
+                tracing::debug!("Collapsing event for {repository} into pending trigger");
+                pending.payload = payload;
+                pending.deliveries.push(delivery.to_string());
+                return "Collapsed into pending trigger";
+            }
+            let deliveries = vec![delivery.to_string()];
+            pending.insert(
+                repository.clone(),
+                Pending {
+                    payload,
+                    deliveries,
+                },
+            );
+        }
+
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            let Some(pending) = webhook.pending.lock().unwrap().remove(&repository) else {
+                return;
+            };
+            tracing::info!("Forwarding latest event for {repository} to Hydra");
+            match webhook.client.push(pending.payload).await {
+                Ok(response) => tracing::info!(?response),
+                Err(err) => {
+                    tracing::error!(?err, "Failed to forward event for {repository}");
+                    for delivery in pending.deliveries {
+                        webhook.deliveries.remove(&delivery).await;
+                    }
+                }
+            }
+        });
+        "Scheduled Hydra trigger"
+    }
+}
+
 #[tracing::instrument(skip_all, err)]
-async fn webhook(State(client): State<HydraClient>, event: String) -> Result<(), AppError> {
-    tracing::info!("Received push event from {event:?}");
-    tracing::trace!(?event);
-    let response = client.push(event).await?;
+async fn webhook(
+    State(webhook): State<Webhook>,
+    headers: HeaderMap,
+    payload: String,
+) -> Result<&'static str, AppError> {
+    let kind = headers
+        .get("X-GitHub-Event")
+        .and_then(|value| value.to_str().ok())
+        .ok_or((StatusCode::BAD_REQUEST, "Missing X-GitHub-Event header"))?;
+    let delivery = headers
+        .get("X-GitHub-Delivery")
+        .and_then(|value| value.to_str().ok())
+        .ok_or((StatusCode::BAD_REQUEST, "Missing X-GitHub-Delivery header"))?;
+    if !webhook.deliveries.insert(delivery).await {
+        tracing::warn!("Rejecting replayed delivery {delivery}");
+        return Err((StatusCode::CONFLICT, "Delivery already received").into());
+    }
+
+    // failed deliveries are forgotten, so that they can be redelivered
+    let result = handle(&webhook, kind, delivery, payload).await;
+    if result.is_err() {
+        webhook.deliveries.remove(delivery).await;
+    }
+    result
+}
+
+async fn handle(
+    webhook: &Webhook,
+    kind: &str,
+    delivery: &str,
+    payload: String,
+) -> Result<&'static str, AppError> {
+    if kind == "ping" {
+        tracing::info!("Received ping");
+        return Ok("pong");
+    }
+    let Ok(kind) = kind.parse::<GitHubEvent>() else {
+        tracing::debug!("Ignoring {kind} event");
+        return Ok("Ignored event type");
+    };
+
+    let event = Event::parse(kind, &payload)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid payload: {err}")))?;
+    if !webhook.config.forwards(&event) {
+        tracing::debug!(?event, "Ignoring event");
+        return Ok("Ignored by rules");
+    }
+
+    if let Some(expect_builds_for) = webhook.config.expect_builds_for {
+        let project = webhook.config.project(&event);
+        let expected = webhook
+            .store
+            .expect_builds(project, Instant::now() + expect_builds_for);
+        tracing::info!(?expected, "Expecting builds for {project}");
+    }
+
+    if let Some(window) = webhook.config.debounce {
+        return Ok(webhook.debounce(window, event.repository, delivery, payload));
+    }
+
+    tracing::info!(?event, "Forwarding event to Hydra");
+    tracing::trace!(?payload);
+    let response = webhook.client.push(payload).await?;
     tracing::info!(?response);
-    Ok(())
+    Ok("Forwarded to Hydra")
 }
 
-pub fn handler(secret: SecretString) -> axum::routing::MethodRouter<HydraClient, Infallible> {
+pub fn handler(secret: SecretString) -> axum::routing::MethodRouter<Webhook, Infallible> {
     post(webhook).route_layer(middleware::from_fn_with_state(
-        secret,
+        Signature::github(secret),
         validate_request_signature,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::HydraAuth, hydra::store::StoreConfig};
+
+    #[tokio::test]
+    async fn redeliver_after_failure() {
+        // nothing listens on the port anymore, so forwarding fails
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let client = HydraClient::new(url.parse().unwrap(), HydraAuth::None).unwrap();
+        let store = Arc::new(Store::new(vec![], StoreConfig::default()));
+        let state = Webhook::new(client, WebhookConfig::default(), store).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-GitHub-Event", "push".parse().unwrap());
+        headers.insert("X-GitHub-Delivery", "1234".parse().unwrap());
+        let payload =
+            r#"{"ref": "refs/heads/main", "repository": {"full_name": "example/nix-config"}}"#;
+        // not rejected as a replay when redelivered
+        for _ in 0..2 {
+            let result = webhook(State(state.clone()), headers.clone(), payload.to_string()).await;
+            assert!(matches!(result, Err(AppError::InternalServerError(_))));
+        }
+        assert!(state.deliveries.insert("1234").await);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::error::AppError;
+use axum::http::HeaderMap;
+use hydra_sentinel::auth::{SIGNATURE_HEADER, TIMESTAMP_HEADER, verify};
+use reqwest::StatusCode;
+use secrecy::{ExposeSecret, SecretString};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Maximum difference between the builder's and the server's clocks
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Signatures accepted recently, so that captured connection requests can't be replayed
+#[derive(Default)]
+pub struct SeenSignatures(Mutex<HashMap<String, u64>>);
+
+impl SeenSignatures {
+    /// Record a signature, returning whether it's new. Signatures are forgotten once their
+    /// timestamp is too old to be accepted anyway.
+    fn insert(&self, signature: &str, timestamp: u64, now: Duration) -> bool {
+        let mut seen = self.0.lock().unwrap();
+        seen.retain(|_, timestamp| now.abs_diff(Duration::from_secs(*timestamp)) <= MAX_CLOCK_SKEW);
+        seen.insert(signature.to_string(), timestamp).is_none()
+    }
+}
+
+/// Verify that a builder connecting as `host_name` knows the secret in `token_file`, and that the
+/// request isn't a replay of an earlier one
+pub async fn authenticate(
+    token_file: &Path,
+    host_name: &str,
+    headers: &HeaderMap,
+    seen: &SeenSignatures,
+    now: SystemTime,
+) -> Result<(), AppError> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| {
+                AppError::from((StatusCode::UNAUTHORIZED, format!("Missing {name} header")))
+            })
+    };
+    let timestamp = header(TIMESTAMP_HEADER)?
+        .parse::<u64>()
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid timestamp: {err}")))?;
+    let signature = header(SIGNATURE_HEADER)?;
+
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .expect("system time to be after the epoch");
+    if now.abs_diff(Duration::from_secs(timestamp)) > MAX_CLOCK_SKEW {
+        return Err(AppError::from((
+            StatusCode::UNAUTHORIZED,
+            format!("{host_name} timestamp too far from server time, check clocks"),
+        )));
+    }
+
+    let secret = tokio::fs::read_to_string(token_file)
+        .await
+        .map(|token| SecretString::from(token.trim()))
+        .map_err(|err| anyhow::anyhow!("Failed to read token for {host_name}: {err}"))?;
+
+    if !verify(
+        secret.expose_secret().as_bytes(),
+        host_name,
+        timestamp,
+        signature,
+    ) {
+        return Err(AppError::from((
+            StatusCode::UNAUTHORIZED,
+            format!("Invalid signature for {host_name}"),
+        )));
+    }
+    if !seen.insert(signature, timestamp, now) {
+        return Err(AppError::from((
+            StatusCode::UNAUTHORIZED,
+            format!("Replayed signature for {host_name}"),
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hydra_sentinel::auth::sign;
+
+    fn headers(timestamp: u64, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, timestamp.to_string().parse().unwrap());
+        headers.insert(SIGNATURE_HEADER, signature.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn authenticate_builder() {
+        let token_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(&token_file, "hocus pocus\n").unwrap();
+        let token_file = token_file.path();
+        let seen = SeenSignatures::default();
+        let now = UNIX_EPOCH + Duration::from_secs(1712425681);
+
+        let signature = sign(b"hocus pocus", "bogus", 1712425681);
+        let valid = headers(1712425681, &signature);
+        assert!(
+            authenticate(token_file, "other", &valid, &seen, now)
+                .await
+                .is_err()
+        );
+        assert!(
+            authenticate(token_file, "bogus", &valid, &seen, now)
+                .await
+                .is_ok()
+        );
+        // the same request can't be used twice
+        assert!(
+            authenticate(
+                token_file,
+                "bogus",
+                &valid,
+                &seen,
+                now + Duration::from_secs(1)
+            )
+            .await
+            .is_err()
+        );
+
+        let later = now + MAX_CLOCK_SKEW + Duration::from_secs(1);
+        let seen = SeenSignatures::default();
+        assert!(
+            authenticate(token_file, "bogus", &valid, &seen, later)
+                .await
+                .is_err()
+        );
+
+        let missing = HeaderMap::new();
+        assert!(
+            authenticate(token_file, "bogus", &missing, &seen, now)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,54 +1,258 @@
-use axum::http::HeaderMap;
-use reqwest::Url;
+use anyhow::Context;
+use axum::http::{
+    header::{COOKIE, SET_COOKIE},
+    HeaderMap, HeaderValue,
+};
+use futures_util::TryStreamExt;
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use secrecy::{ExposeSecret, SecretString};
 use serde::{de, Deserialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+use tokio_util::io::{StreamReader, SyncIoBridge};
 
-use crate::model::System;
+use crate::{config::HydraAuth, model::System};
 
 /// https://editor.swagger.io/?url=https://raw.githubusercontent.com/NixOS/hydra/master/hydra-api.yaml
 #[derive(Clone)]
 pub struct HydraClient {
     base_url: Url,
     client: reqwest::Client,
+    auth: Arc<Auth>,
+}
+
+/// Runtime form of [`HydraAuth`], with any secrets loaded
+enum Auth {
+    None,
+    Login {
+        username: String,
+        password: SecretString,
+        /// Cookie of the current session, if logged in
+        session: Mutex<Option<HeaderValue>>,
+    },
+    Bearer(SecretString),
+    Basic {
+        username: String,
+        password: SecretString,
+    },
+}
+
+impl Auth {
+    fn load(config: HydraAuth) -> anyhow::Result<Self> {
+        Ok(match config {
+            HydraAuth::None => Auth::None,
+            HydraAuth::Login { credentials_file } => {
+                let (username, password) = read_credentials(&credentials_file)?;
+                Auth::Login {
+                    username,
+                    password,
+                    session: Mutex::new(None),
+                }
+            }
+            HydraAuth::Bearer { token_file } => Auth::Bearer(
+                std::fs::read_to_string(&token_file)
+                    .map(|token| SecretString::from(token.trim()))
+                    .with_context(|| format!("Failed to read Hydra token {token_file:?}"))?,
+            ),
+            HydraAuth::Basic { credentials_file } => {
+                let (username, password) = read_credentials(&credentials_file)?;
+                Auth::Basic { username, password }
+            }
+        })
+    }
+}
+
+fn read_credentials(path: &Path) -> anyhow::Result<(String, SecretString)> {
+    let credentials = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read Hydra credentials {path:?}"))?;
+    parse_credentials(&credentials)
+        .with_context(|| format!("Expected username:password in {path:?}"))
+}
+
+fn parse_credentials(credentials: &str) -> Option<(String, SecretString)> {
+    let (username, password) = credentials.trim().split_once(':')?;
+    Some((username.to_string(), SecretString::from(password)))
+}
+
+/// The `Cookie` header value that sends back the cookies set by a response
+fn session_cookie(headers: &HeaderMap) -> Option<HeaderValue> {
+    let cookies = headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok()?.split(';').next())
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty())
+        .collect::<Vec<_>>();
+    if cookies.is_empty() {
+        return None;
+    }
+    let mut cookie = HeaderValue::from_str(&cookies.join("; ")).ok()?;
+    cookie.set_sensitive(true);
+    Some(cookie)
 }
 
 impl HydraClient {
-    pub fn new(base_url: Url) -> Self {
+    pub fn new(base_url: Url, auth: HydraAuth) -> anyhow::Result<Self> {
         let mut headers = HeaderMap::new();
         headers.insert("Accept", "application/json".parse().unwrap());
         headers.insert("Referer", base_url.to_string().parse().unwrap()); // bypass XSRF check
-        Self {
+        Ok(Self {
             base_url,
             client: reqwest::Client::builder()
                 .default_headers(headers)
                 .build()
                 .unwrap(),
-        }
+            auth: Arc::new(Auth::load(auth)?),
+        })
     }
 
-    pub async fn push(&self, event: String) -> anyhow::Result<Value> {
-        let mut url = self.base_url.join("/api/push-github")?;
-        // https://github.com/NixOS/hydra/commit/916531dc9ccee52e6dab256232933fcf6d198158
+    /// Send an authenticated request, failing on error responses
+    async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let response = match &*self.auth {
+            Auth::None => request.send().await?,
+            Auth::Bearer(token) => request.bearer_auth(token.expose_secret()).send().await?,
+            Auth::Basic { username, password } => {
+                request
+                    .basic_auth(username, Some(password.expose_secret()))
+                    .send()
+                    .await?
+            }
+            Auth::Login { .. } => {
+                // retried once in case the session expired
+                let retry = request.try_clone();
+                let cookie = self.session(None).await?;
+                let response = request.header(COOKIE, cookie.clone()).send().await?;
+                match retry {
+                    Some(retry)
+                        if matches!(
+                            response.status(),
+                            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED
+                        ) =>
+                    {
+                        tracing::info!("Hydra session expired, logging in again");
+                        let cookie = self.session(Some(&cookie)).await?;
+                        retry.header(COOKIE, cookie).send().await?
+                    }
+                    _ => response,
+                }
+            }
+        };
+        response.error_for_status_with_body().await
+    }
+
+    /// Cookie of the current session, logging in if there is none yet or it is `expired`
+    async fn session(&self, expired: Option<&HeaderValue>) -> anyhow::Result<HeaderValue> {
+        let Auth::Login {
+            username,
+            password,
+            session,
+        } = &*self.auth
+        else {
+            anyhow::bail!("Not configured to log in to Hydra");
+        };
+
+        // held while logging in, so that concurrent requests share one session
+        let mut session = session.lock().await;
+        if let Some(cookie) = &*session {
+            if Some(cookie) != expired {
+                return Ok(cookie.clone());
+            }
+        }
+
         let response = self
             .client
-            .post(url)
-            .body(event)
+            .post(self.base_url.join("login")?)
+            .json(&json!({
+                "username": username,
+                "password": password.expose_secret(),
+            }))
             .send()
             .await?
             .error_for_status_with_body()
-            .await?;
+            .await
+            .context("Failed to log in to Hydra")?;
+        let cookie = session_cookie(response.headers())
+            .context("Hydra did not set a session cookie on login")?;
+        tracing::debug!("Logged in to Hydra as {username}");
+        *session = Some(cookie.clone());
+        Ok(cookie)
+    }
+
+    pub async fn push(&self, event: String) -> anyhow::Result<Value> {
+        let mut url = self.base_url.join("/api/push-github")?;
+        // https://github.com/NixOS/hydra/commit/916531dc9ccee52e6dab256232933fcf6d198158
+        let response = self.send(self.client.post(url).body(event)).await?;
         Ok(response.json().await?)
     }
 
-    pub async fn get_queue(&self) -> anyhow::Result<Vec<Build>> {
-        let url = self.base_url.join("queue")?;
-        let response = self
-            .client
-            .get(url)
-            .send()
-            .await?
-            .error_for_status_with_body()
-            .await?;
+    pub async fn push_gitea(&self, event: String) -> anyhow::Result<Value> {
+        let url = self.base_url.join("/api/push-gitea")?;
+        let response = self.send(self.client.post(url).body(event)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Trigger evaluation of the given `project:jobset`s, and of jobsets with one of `repos` as
+    /// an input
+    pub async fn push_jobsets(
+        &self,
+        jobsets: &[String],
+        repos: &[String],
+    ) -> anyhow::Result<Value> {
+        let mut url = self.base_url.join("/api/push")?;
+        url.query_pairs_mut()
+            .append_pair("jobsets", &jobsets.join(","))
+            .append_pair("repos", &repos.join(","));
+        let response = self.send(self.client.post(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Unfinished builds, highest priority first. With a `limit`, only that many are fetched, from
+    /// the `/api/queue` endpoint.
+    pub async fn get_queue(&self, limit: Option<u32>) -> anyhow::Result<Queue> {
+        let url = match limit {
+            Some(limit) => {
+                let mut url = self.base_url.join("api/queue")?;
+                url.query_pairs_mut().append_pair("nr", &limit.to_string());
+                url
+            }
+            None => self.base_url.join("queue")?,
+        };
+        let response = self.send(self.client.get(url)).await?;
+
+        // deserialized as it arrives, so the whole queue is never held in memory
+        let body = StreamReader::new(response.bytes_stream().map_err(io::Error::other));
+        let body = BufReader::new(SyncIoBridge::new(body));
+        let (builds, entries) =
+            tokio::task::spawn_blocking(move || deserialize_queue(body)).await??;
+        Ok(Queue {
+            builds,
+            truncated: limit.is_some_and(|limit| entries >= limit as usize),
+        })
+    }
+
+    /// Derivation of a build, which the queue doesn't include
+    pub async fn get_drv_path(&self, id: u64) -> anyhow::Result<Option<String>> {
+        #[derive(Deserialize)]
+        struct BuildDetails {
+            drvpath: Option<String>,
+        }
+
+        let url = self.base_url.join(&format!("build/{id}"))?;
+        let response = self.send(self.client.get(url)).await?;
+        let build: BuildDetails = response.json().await?;
+        Ok(build.drvpath)
+    }
+
+    /// Build steps currently running on build machines
+    pub async fn get_status(&self) -> anyhow::Result<Vec<BuildStep>> {
+        let url = self.base_url.join("status")?;
+        let response = self.send(self.client.get(url)).await?;
         let body = response.json().await?;
         Ok(body)
     }
@@ -78,24 +282,84 @@ impl ResponseExt for reqwest::Response {
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// Builds in Hydra's queue
+#[derive(Debug)]
+pub struct Queue {
+    pub builds: Vec<Build>,
+    /// Whether the limit was reached, so there may be more builds
+    pub truncated: bool,
+}
+
+/// A queued build. Only the fields needed to decide which builders to wake are deserialized.
+#[derive(Debug)]
 pub struct Build {
+    pub id: u64,
     pub project: String,
-    pub jobset: String,
-    #[serde(deserialize_with = "int_to_bool")]
-    pub finished: bool,
-    pub starttime: Option<u32>,
-    pub stoptime: Option<u32>,
-    pub buildstatus: Option<u32>,
     pub system: System,
+    pub drvpath: Option<String>,
+}
+
+/// Deserialize the builds in a queue one at a time, skipping those for systems we have no
+/// builders for, without keeping the fields we don't need. Also returns the number of entries,
+/// including those skipped.
+fn deserialize_queue(body: impl io::Read) -> serde_json::Result<(Vec<Build>, usize)> {
+    #[derive(Deserialize)]
+    struct Entry {
+        id: u64,
+        project: String,
+        system: String,
+        drvpath: Option<String>,
+    }
+
+    struct QueueVisitor;
+
+    impl<'de> de::Visitor<'de> for QueueVisitor {
+        type Value = (Vec<Build>, usize);
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a list of builds")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let mut builds = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            let mut entries = 0;
+            while let Some(entry) = seq.next_element::<Entry>()? {
+                entries += 1;
+                match entry.system.parse() {
+                    Ok(system) => builds.push(Build {
+                        id: entry.id,
+                        project: entry.project,
+                        system,
+                        drvpath: entry.drvpath,
+                    }),
+                    Err(_) => tracing::trace!("Skipping build for system {}", entry.system),
+                }
+            }
+            Ok((builds, entries))
+        }
+    }
+
+    let mut deserializer = serde_json::Deserializer::from_reader(body);
+    let builds = de::Deserializer::deserialize_seq(&mut deserializer, QueueVisitor)?;
+    deserializer.end()?;
+    Ok(builds)
 }
 
-fn int_to_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
-where
-    D: de::Deserializer<'de>,
-{
-    let s: u32 = de::Deserialize::deserialize(deserializer)?;
-    Ok(s != 0)
+#[derive(Deserialize, Debug)]
+pub struct BuildStep {
+    pub build: u64,
+    /// Machine the step runs on, as listed in the machines file, e.g. `ssh://builder`
+    pub machine: String,
+}
+
+impl BuildStep {
+    /// Host name of the machine the step runs on
+    pub fn host_name(&self) -> &str {
+        crate::queue::machine_host_name(&self.machine)
+    }
 }
 
 #[cfg(test)]
@@ -104,10 +368,126 @@ mod tests {
 
     #[test]
     fn deserialize_build() {
-        let build = serde_json::from_str::<Vec<Build>>(include_str!("../../test/hydra-queue.json"))
-            .unwrap();
+        let (build, entries) =
+            deserialize_queue(&include_bytes!("../../test/hydra-queue.json")[..]).unwrap();
+
+        assert_eq!(build.len(), 3);
+        assert_eq!(entries, 3);
+        assert_eq!(build[0].id, 199);
+        assert_eq!(build[0].system, System::Aarch64Linux);
+        // the queue doesn't include derivations
+        assert!(build.iter().all(|build| build.drvpath.is_none()));
+    }
+
+    #[test]
+    fn skip_unsupported_systems() {
+        let mut queue: Vec<Value> =
+            serde_json::from_str(include_str!("../../test/hydra-queue.json")).unwrap();
+        queue[0]["system"] = "riscv64-linux".into();
+        let (build, entries) = deserialize_queue(&serde_json::to_vec(&queue).unwrap()[..]).unwrap();
+
+        assert_eq!(entries, 3);
+        assert_eq!(build.len(), 2);
+        assert_eq!(build[0].id, 200);
+    }
+
+    /// Serves the recorded queue, truncated to `nr` builds on `/api/queue`
+    async fn serve_queue() -> (Url, Arc<std::sync::Mutex<Vec<String>>>) {
+        use axum::{extract::RawQuery, routing::get, Json, Router};
+
+        let queue: Vec<Value> =
+            serde_json::from_str(include_str!("../../test/hydra-queue.json")).unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route(
+                "/queue",
+                get({
+                    let queue = queue.clone();
+                    let requests = requests.clone();
+                    move || async move {
+                        requests.lock().unwrap().push("/queue".to_string());
+                        Json(queue)
+                    }
+                }),
+            )
+            .route(
+                "/api/queue",
+                get({
+                    let requests = requests.clone();
+                    move |RawQuery(query): RawQuery| async move {
+                        let query = query.unwrap_or_default();
+                        requests.lock().unwrap().push(format!("/api/queue?{query}"));
+                        let nr = query
+                            .strip_prefix("nr=")
+                            .and_then(|nr| nr.parse().ok())
+                            .unwrap_or(queue.len());
+                        Json(queue[..nr.min(queue.len())].to_vec())
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url.parse().unwrap(), requests)
+    }
+
+    #[tokio::test]
+    async fn get_limited_queue() {
+        let (url, requests) = serve_queue().await;
+        let client = HydraClient::new(url, HydraAuth::None).unwrap();
+
+        let queue = client.get_queue(None).await.unwrap();
+        assert_eq!(queue.builds.len(), 3);
+        assert!(!queue.truncated);
+
+        let queue = client.get_queue(Some(2)).await.unwrap();
+        assert_eq!(queue.builds.len(), 2);
+        assert!(queue.truncated);
+
+        let queue = client.get_queue(Some(5)).await.unwrap();
+        assert_eq!(queue.builds.len(), 3);
+        assert!(!queue.truncated);
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            ["/queue", "/api/queue?nr=2", "/api/queue?nr=5"]
+        );
+    }
+
+    #[test]
+    fn deserialize_build_step() {
+        let steps =
+            serde_json::from_str::<Vec<BuildStep>>(include_str!("../../test/hydra-status.json"))
+                .unwrap();
+
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].build, 199);
+        assert_eq!(steps[0].host_name(), "bogus");
+        assert_eq!(steps[1].host_name(), "other");
+    }
+
+    #[test]
+    fn credentials() {
+        let (username, password) = parse_credentials("sentinel:hocus:pocus\n").unwrap();
+        assert_eq!(username, "sentinel");
+        assert_eq!(password.expose_secret(), "hocus:pocus");
+        assert!(parse_credentials("sentinel").is_none());
+    }
+
+    #[test]
+    fn login_session_cookie() {
+        let mut headers = HeaderMap::new();
+        assert!(session_cookie(&headers).is_none());
 
-        dbg!(build);
+        headers.append(
+            SET_COOKIE,
+            "hydra_session=abc123; path=/; HttpOnly".parse().unwrap(),
+        );
+        headers.append(SET_COOKIE, "other=def".parse().unwrap());
+        assert_eq!(
+            session_cookie(&headers).unwrap(),
+            "hydra_session=abc123; other=def"
+        );
     }
 
     // TODO: integration tests
@@ -1,3 +1,6 @@
+pub mod auth;
 pub mod client;
+pub mod registration;
+pub mod scheduler;
 pub mod store;
 pub mod websocket;
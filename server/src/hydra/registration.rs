@@ -0,0 +1,85 @@
+use crate::{config::RegistrationPolicy, error::AppError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use secrecy::{ExposeSecret, SecretString};
+use std::collections::HashSet;
+use subtle::ConstantTimeEq;
+
+/// Runtime form of [`RegistrationPolicy`], with any secrets loaded
+pub enum Registration {
+    Disabled,
+    AllowList(HashSet<String>),
+    Token(SecretString),
+    Open,
+}
+
+impl Registration {
+    pub fn load(policy: RegistrationPolicy) -> anyhow::Result<Self> {
+        Ok(match policy {
+            RegistrationPolicy::Disabled => Registration::Disabled,
+            RegistrationPolicy::AllowList { host_names } => Registration::AllowList(host_names),
+            RegistrationPolicy::Token { token_file } => Registration::Token(
+                std::fs::read_to_string(&token_file)
+                    .map(|token| SecretString::from(token.trim()))
+                    .with_context(|| format!("Failed to read registration token {token_file:?}"))?,
+            ),
+            RegistrationPolicy::Open => Registration::Open,
+        })
+    }
+
+    /// Whether an unknown builder connecting as `host_name` may attempt to register
+    pub fn admits(&self, host_name: &str) -> bool {
+        match self {
+            Registration::Disabled => false,
+            Registration::AllowList(host_names) => host_names.contains(host_name),
+            Registration::Token(_) | Registration::Open => true,
+        }
+    }
+
+    pub fn verify(&self, host_name: &str, token: Option<&str>) -> Result<(), AppError> {
+        if !self.admits(host_name) {
+            return Err(AppError::from((
+                StatusCode::FORBIDDEN,
+                format!("{host_name} is not allowed to register"),
+            )));
+        }
+
+        if let Registration::Token(expected) = self {
+            let matches = token.is_some_and(|token| {
+                token
+                    .as_bytes()
+                    .ct_eq(expected.expose_secret().as_bytes())
+                    .into()
+            });
+            if !matches {
+                return Err(AppError::from((
+                    StatusCode::FORBIDDEN,
+                    format!("{host_name} presented an invalid registration token"),
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_token() {
+        let registration = Registration::Token(SecretString::from("hocus pocus"));
+        assert!(registration.verify("bogus", Some("hocus pocus")).is_ok());
+        assert!(registration.verify("bogus", Some("abracadabra")).is_err());
+        assert!(registration.verify("bogus", None).is_err());
+    }
+
+    #[test]
+    fn verify_allow_list() {
+        let registration = Registration::AllowList(["bogus".to_string()].into());
+        assert!(registration.verify("bogus", None).is_ok());
+        assert!(registration.verify("other", None).is_err());
+        assert!(Registration::Disabled.verify("bogus", None).is_err());
+    }
+}
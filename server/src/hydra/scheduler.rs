@@ -0,0 +1,195 @@
+//! Decides which builders to wake for the queued builds
+
+use crate::model::{BuildMachine, QueuedBuild};
+use std::{cmp::Reverse, collections::HashMap};
+
+/// Choose which of the `offline` builders to wake, so that together with the spare capacity of
+/// the `connected` ones (less the steps `running` on them) there is enough capacity for the
+/// queued builds. Builders are woken in order of priority, then speed. Last resort builders are
+/// only woken for builds no other builder can run.
+pub fn machines_to_wake<'a>(
+    queued: &HashMap<QueuedBuild, usize>,
+    running: &HashMap<String, usize>,
+    connected: &[BuildMachine],
+    offline: &'a [BuildMachine],
+) -> Vec<&'a BuildMachine> {
+    let mut backlog = queued.clone();
+    backlog.retain(|_, count| *count > 0);
+    for builder in connected {
+        let busy = running.get(builder.host_name()).copied().unwrap_or(0);
+        assign(
+            &mut backlog,
+            builder,
+            builder.capacity().saturating_sub(busy),
+        );
+    }
+
+    let mut candidates = offline.iter().collect::<Vec<_>>();
+    candidates.sort_by_key(|builder| {
+        (
+            Reverse(builder.policy.priority),
+            Reverse(builder.speed_factor()),
+            builder.host_name(),
+        )
+    });
+    let (last_resort, candidates): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|builder| builder.policy.last_resort);
+
+    let mut to_wake = Vec::new();
+    wake_for_backlog(&mut backlog, candidates, &mut to_wake);
+
+    backlog.retain(|build, _| {
+        !connected
+            .iter()
+            .chain(offline)
+            .any(|builder| !builder.policy.last_resort && builder.can_build(build))
+    });
+    wake_for_backlog(&mut backlog, last_resort, &mut to_wake);
+
+    to_wake
+}
+
+fn wake_for_backlog<'a>(
+    backlog: &mut HashMap<QueuedBuild, usize>,
+    candidates: Vec<&'a BuildMachine>,
+    to_wake: &mut Vec<&'a BuildMachine>,
+) {
+    for builder in candidates {
+        if backlog.is_empty() {
+            break;
+        }
+        if assign(backlog, builder, builder.capacity()) {
+            to_wake.push(builder);
+        }
+    }
+}
+
+/// Take as many builds off the `backlog` as `builder` can run with its `capacity`. Returns whether
+/// it took any.
+fn assign(
+    backlog: &mut HashMap<QueuedBuild, usize>,
+    builder: &BuildMachine,
+    mut capacity: usize,
+) -> bool {
+    let mut assigned = false;
+    backlog.retain(|build, count| {
+        if capacity == 0 || !builder.can_build(build) {
+            return true;
+        }
+        let taken = capacity.min(*count);
+        capacity -= taken;
+        *count -= taken;
+        assigned = true;
+        *count > 0
+    });
+    assigned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{BuildMachineSpec, System};
+
+    fn builder(host_name: &str, max_jobs: u32, speed_factor: u32) -> BuildMachine {
+        BuildMachine::from(BuildMachineSpec {
+            ssh_user: None,
+            host_name: host_name.into(),
+            ssh_key: None,
+            systems: [System::X86_64Linux].into(),
+            supported_features: Default::default(),
+            mandatory_features: Default::default(),
+            max_jobs: Some(max_jobs),
+            speed_factor: Some(speed_factor),
+            public_host_key: None,
+        })
+    }
+
+    fn host_names(builders: Vec<&BuildMachine>) -> Vec<&str> {
+        builders.into_iter().map(BuildMachine::host_name).collect()
+    }
+
+    #[test]
+    fn wake_for_backlog() {
+        let queued = |count| HashMap::from([(QueuedBuild::from(System::X86_64Linux), count)]);
+        let offline = [builder("slow", 4, 1), builder("fast", 2, 4)];
+
+        assert!(machines_to_wake(&queued(0), &HashMap::new(), &[], &offline).is_empty());
+        assert_eq!(
+            host_names(machines_to_wake(&queued(1), &HashMap::new(), &[], &offline)),
+            ["fast"]
+        );
+        assert_eq!(
+            host_names(machines_to_wake(&queued(3), &HashMap::new(), &[], &offline)),
+            ["fast", "slow"]
+        );
+
+        // connected builders take their share first
+        let connected = [builder("bogus", 2, 1)];
+        assert!(machines_to_wake(&queued(2), &HashMap::new(), &connected, &offline).is_empty());
+        assert_eq!(
+            host_names(machines_to_wake(
+                &queued(3),
+                &HashMap::new(),
+                &connected,
+                &offline
+            )),
+            ["fast"]
+        );
+    }
+
+    #[test]
+    fn wake_for_unsupported_builds() {
+        let mut kvm = builder("kvm", 1, 1);
+        kvm.spec.supported_features = ["kvm".to_string()].into();
+        let queued = HashMap::from([(
+            QueuedBuild {
+                system: System::X86_64Linux,
+                required_features: ["kvm".to_string()].into(),
+            },
+            1,
+        )]);
+
+        // connected capacity is no use if it can't run the build
+        let connected = [builder("bogus", 8, 1)];
+        let offline = [builder("fast", 8, 4), kvm];
+        assert_eq!(
+            host_names(machines_to_wake(
+                &queued,
+                &HashMap::new(),
+                &connected,
+                &offline
+            )),
+            ["kvm"]
+        );
+    }
+
+    #[test]
+    fn wake_by_policy() {
+        let queued = |count| HashMap::from([(QueuedBuild::from(System::X86_64Linux), count)]);
+        let mut laptop = builder("laptop", 8, 8);
+        laptop.policy.last_resort = true;
+        let mut desktop = builder("desktop", 1, 1);
+        desktop.policy.priority = 1;
+        let offline = [laptop, desktop, builder("fast", 1, 4)];
+
+        assert_eq!(
+            host_names(machines_to_wake(&queued(1), &HashMap::new(), &[], &offline)),
+            ["desktop"]
+        );
+        // a lack of capacity isn't enough to wake the laptop
+        assert_eq!(
+            host_names(machines_to_wake(&queued(8), &HashMap::new(), &[], &offline)),
+            ["desktop", "fast"]
+        );
+        assert_eq!(
+            host_names(machines_to_wake(
+                &queued(1),
+                &HashMap::new(),
+                &[],
+                &offline[..1]
+            )),
+            ["laptop"]
+        );
+    }
+}
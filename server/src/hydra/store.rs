@@ -1,46 +1,158 @@
 use crate::{
+    config::{KeepAwakeConfig, WakeRetryConfig},
     error::AppError,
-    model::{BuildMachine, MacAddress, System},
+    model::{BuildMachine, BuildMachineSpec, PowerDown, QueuedBuild, WakeMethod},
 };
+use chrono::{Local, NaiveDate};
+use hydra_sentinel::ServerMessage;
 use reqwest::StatusCode;
 use std::{
     collections::{HashMap, HashSet},
     convert::Infallible,
     fs, iter,
-    net::Ipv4Addr,
+    net::IpAddr,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
     time::{Duration, Instant},
 };
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
-    net::UdpSocket,
-    sync::watch::{Receiver, Sender, channel, error::RecvError},
+    sync::{
+        mpsc,
+        watch::{Receiver, Sender, channel},
+    },
 };
 
-use super::client::HydraClient;
+use super::{auth::SeenSignatures, registration::Registration, scheduler};
+
+/// Builders that haven't connected this long after being woken are no longer considered waking
+const WAKING_FOR: Duration = Duration::from_secs(5 * 60);
+
+/// Attempts to wake a builder since it last connected
+#[derive(Default)]
+struct WakeState {
+    first_attempt: Option<Instant>,
+    last_attempt: Option<Instant>,
+    attempts: u32,
+    /// An attempt failed outright, so the next one uses the fallback
+    failed: bool,
+    /// How long the builder took to connect after it was last woken
+    time_to_connect: Option<Duration>,
+    /// Waking the builder was given up on until it connects by itself
+    unreachable: bool,
+}
+
+/// An attempt to wake a builder that's due
+#[derive(Debug)]
+pub struct WakeAttempt {
+    pub builder: BuildMachine,
+    pub method: WakeMethod,
+    /// Starting at 1 for the first attempt since the builder last connected
+    pub attempt: u32,
+}
+
+struct Connection {
+    connected_at: Instant,
+    last_seen: Instant,
+    /// When the first attempt to wake the builder was made, by any method and regardless of
+    /// retries, if it was woken
+    woken_at: Option<Instant>,
+    /// When the builder last had something to build
+    last_wanted: Option<Instant>,
+    /// Since when the builder hasn't been kept awake, if it isn't
+    idle_since: Option<Instant>,
+    /// Whether the builder was asked to power down
+    powering_down: bool,
+    /// Address the builder connected from, and where to send it messages to relay, once its
+    /// connection is up
+    relay: Option<(IpAddr, mpsc::UnboundedSender<ServerMessage>)>,
+}
 
 // TODO: Get rid of mutexes
 pub struct Store {
     builders: HashMap<String, BuildMachine>,
-    last_seen: Mutex<HashMap<String, Instant>>,
-    queued_systems: Mutex<HashSet<System>>,
+    /// Specs reported by the builders themselves
+    registered: Mutex<HashMap<String, BuildMachineSpec>>,
+    registration: Registration,
+    seen_signatures: SeenSignatures,
+    connections: Mutex<HashMap<String, Connection>>,
+    /// Time each builder spent connected on the given day, excluding current connections
+    awake: Mutex<HashMap<String, (NaiveDate, Duration)>>,
+    /// Attempts to wake each builder
+    waking: Mutex<HashMap<String, WakeState>>,
+    /// Number of queued builds of each kind
+    queued: Mutex<HashMap<QueuedBuild, usize>>,
+    /// Number of build steps running on each builder
+    running: Mutex<HashMap<String, usize>>,
+    /// Kinds of builds seen for each Hydra project, to anticipate its builds
+    history: Mutex<HashMap<String, HashSet<QueuedBuild>>>,
+    /// Builds expected soon, e.g. after a push, and until when
+    expected: Mutex<HashMap<QueuedBuild, Instant>>,
     stale_after: Duration,
+    keep_awake: KeepAwakeConfig,
+    reevaluate_interval: Duration,
+    wake_retries: WakeRetryConfig,
+    shutting_down: AtomicBool,
     changed: Sender<()>,
 }
 
+/// Settings of a [`Store`]
+pub struct StoreConfig {
+    /// Consider builders dead after not hearing from them this long
+    pub stale_after: Duration,
+    pub registration: Registration,
+    pub keep_awake: KeepAwakeConfig,
+    /// How often to re-evaluate which builders to wake or keep awake
+    pub reevaluate_interval: Duration,
+    pub wake_retries: WakeRetryConfig,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            stale_after: Duration::from_secs(60),
+            registration: Registration::Disabled,
+            keep_awake: KeepAwakeConfig::default(),
+            reevaluate_interval: Duration::from_secs(30),
+            wake_retries: WakeRetryConfig::default(),
+        }
+    }
+}
+
 impl Store {
-    pub fn new(stale_after: Duration, builders: impl IntoIterator<Item = BuildMachine>) -> Self {
+    pub fn new(builders: impl IntoIterator<Item = BuildMachine>, config: StoreConfig) -> Self {
+        let StoreConfig {
+            stale_after,
+            registration,
+            keep_awake,
+            reevaluate_interval,
+            wake_retries,
+        } = config;
         let (changed, _) = channel(());
         Store {
             builders: builders
                 .into_iter()
                 .map(|b| (b.host_name().to_string(), b))
                 .collect(),
-            last_seen: Mutex::new(HashMap::new()),
-            queued_systems: Mutex::new(HashSet::new()),
+            registered: Mutex::new(HashMap::new()),
+            registration,
+            seen_signatures: SeenSignatures::default(),
+            connections: Mutex::new(HashMap::new()),
+            awake: Mutex::new(HashMap::new()),
+            waking: Mutex::new(HashMap::new()),
+            queued: Mutex::new(HashMap::new()),
+            running: Mutex::new(HashMap::new()),
+            history: Mutex::new(HashMap::new()),
+            expected: Mutex::new(HashMap::new()),
             stale_after,
+            keep_awake,
+            reevaluate_interval,
+            wake_retries,
+            shutting_down: AtomicBool::new(false),
             changed,
         }
     }
@@ -49,203 +161,676 @@ impl Store {
         self.changed.subscribe()
     }
 
-    // TODO: Cleanup error handling
+    /// How often builders should send heartbeats to avoid being considered stale
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.stale_after / 2
+    }
+
+    /// How often decisions should be re-evaluated even if nothing changed, e.g. because time
+    /// passed
+    pub fn reevaluate_interval(&self) -> Duration {
+        self.reevaluate_interval
+    }
+
+    /// How long to wait for an attempt to wake a builder
+    pub fn wake_attempt_timeout(&self) -> Duration {
+        self.wake_retries.attempt_timeout
+    }
+
+    /// Whether a builder was woken recently and hasn't connected yet
+    pub fn is_waking(&self, now: Instant) -> bool {
+        self.waking.lock().unwrap().values().any(|state| {
+            !state.unreachable
+                && state
+                    .last_attempt
+                    .is_some_and(|at| now.saturating_duration_since(at) < WAKING_FOR)
+        })
+    }
+
+    /// Ask connected builders to disconnect
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        let _ = self.changed.send(());
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    fn builder(&self, host_name: &str) -> Option<BuildMachine> {
+        let registered = self.registered.lock().unwrap();
+        match (self.builders.get(host_name), registered.get(host_name)) {
+            (Some(builder), Some(reported)) => Some(builder.with_reported(reported)),
+            (Some(builder), None) => Some(builder.clone()),
+            (None, Some(reported)) => Some(BuildMachine::from(reported.clone())),
+            (None, None) => None,
+        }
+    }
+
+    fn all_builders(&self) -> Vec<BuildMachine> {
+        let host_names = self
+            .builders
+            .keys()
+            .chain(self.registered.lock().unwrap().keys())
+            .cloned()
+            .collect::<HashSet<_>>();
+        host_names
+            .iter()
+            .filter_map(|host_name| self.builder(host_name))
+            .collect()
+    }
+
+    /// Whether builders (or their VMs) differ in supported or mandatory features, i.e. whether
+    /// a build's required features can affect which builder is woken for it
+    pub fn features_differ(&self) -> bool {
+        let features = self
+            .all_builders()
+            .into_iter()
+            .flat_map(|builder| iter::once(builder.spec).chain(builder.vms))
+            .map(|spec| (spec.supported_features, spec.mandatory_features))
+            .collect::<HashSet<_>>();
+        features.len() > 1
+    }
+
+    /// Whether the builder is listed in `buildMachines`, rather than registering itself
+    pub fn is_configured(&self, host_name: &str) -> bool {
+        self.builders.contains_key(host_name)
+    }
+
+    /// Shared secret the builder must authenticate with, if any
+    pub fn token_file(&self, host_name: &str) -> Option<PathBuf> {
+        self.builder(host_name)?.token_file
+    }
+
+    /// Signatures builders authenticated with recently
+    pub fn seen_signatures(&self) -> &SeenSignatures {
+        &self.seen_signatures
+    }
+
+    /// Whether an unknown builder may attempt to register as `host_name`
+    pub fn admits(&self, host_name: &str) -> bool {
+        self.registration.admits(host_name)
+    }
+
+    /// Record the spec reported by a builder. Unknown builders are subject to the registration
+    /// policy; for statically configured builders, the reported spec only fills in fields left
+    /// unset in the config.
+    pub fn register(
+        &self,
+        mut spec: BuildMachineSpec,
+        token: Option<&str>,
+    ) -> Result<(), AppError> {
+        let host_name = spec.host_name.clone();
+        if !self.builders.contains_key(&host_name) {
+            self.registration.verify(&host_name, token)?;
+            // the key would end up in the machines file, pointing Hydra at any file on the server
+            if let Some(ssh_key) = spec.ssh_key.take() {
+                tracing::warn!("Ignoring SSH key {ssh_key:?} reported by {host_name}");
+            }
+        }
+
+        let mut registered = self.registered.lock().unwrap();
+        let changed = registered
+            .get(&host_name)
+            .is_none_or(|current| current.to_string() != spec.to_string());
+        registered.insert(host_name.clone(), spec);
+        drop(registered);
+
+        if changed {
+            tracing::info!("Registered builder {host_name}");
+            let _ = self.changed.send(());
+        }
+        Ok(())
+    }
+
+    /// Connect a builder listed in `buildMachines`
     pub fn connect(
         self: &Arc<Self>,
         host_name: &str,
         now: Instant,
     ) -> Result<BuilderHandle, AppError> {
-        let Some(builder) = self.builders.get(host_name).cloned() else {
+        // builders that registered themselves must present their registration again
+        if !self.is_configured(host_name) {
             return Err(AppError::from((
                 StatusCode::BAD_REQUEST,
-                "Unknown builder: {host_name}",
+                format!("Unknown builder: {host_name}"),
             )));
         };
+        self.add_connection(host_name, now)
+    }
 
-        let mut last_seen = self.last_seen.lock().unwrap();
+    /// Register a builder and connect it. The registration policy is checked on every
+    /// connection, not just the first one.
+    pub fn connect_registered(
+        self: &Arc<Self>,
+        spec: BuildMachineSpec,
+        token: Option<&str>,
+        now: Instant,
+    ) -> Result<BuilderHandle, AppError> {
+        let host_name = spec.host_name.clone();
+        self.register(spec, token)?;
+        self.add_connection(&host_name, now)
+    }
+
+    // TODO: Cleanup error handling
+    fn add_connection(
+        self: &Arc<Self>,
+        host_name: &str,
+        now: Instant,
+    ) -> Result<BuilderHandle, AppError> {
+        let mut connections = self.connections.lock().unwrap();
 
-        if last_seen.contains_key(host_name) {
+        if connections.contains_key(host_name) {
             return Err(AppError::from((
                 StatusCode::BAD_REQUEST,
-                "{host_name} already connected",
+                format!("{host_name} already connected"),
             )));
         }
-        last_seen.insert(host_name.to_string(), now);
+        let woken_at = self
+            .waking
+            .lock()
+            .unwrap()
+            .get_mut(host_name)
+            .and_then(|state| {
+                if let Some(last_attempt) = state.last_attempt {
+                    let time_to_connect = now.saturating_duration_since(last_attempt);
+                    tracing::info!(
+                        "{host_name} connected {time_to_connect:?} after wake attempt {}",
+                        state.attempts
+                    );
+                    state.time_to_connect = Some(time_to_connect);
+                }
+                let first_attempt = state.first_attempt;
+                *state = WakeState {
+                    time_to_connect: state.time_to_connect,
+                    ..WakeState::default()
+                };
+                first_attempt
+            });
+        connections.insert(
+            host_name.to_string(),
+            Connection {
+                connected_at: now,
+                last_seen: now,
+                woken_at,
+                last_wanted: None,
+                idle_since: None,
+                powering_down: false,
+                relay: None,
+            },
+        );
 
-        drop(last_seen);
+        drop(connections);
 
         let _ = self.changed.send(());
 
         Ok(BuilderHandle {
             store: self.clone(),
-            builder,
+            host_name: host_name.to_string(),
         })
     }
 
     fn disconnect(&self, host_name: &str) {
-        let mut last_seen = self.last_seen.lock().unwrap();
+        let mut connections = self.connections.lock().unwrap();
 
-        let changed = last_seen.remove(host_name).is_some();
-        drop(last_seen);
+        let removed = connections.remove(host_name);
+        drop(connections);
 
-        if changed {
+        if let Some(connection) = removed {
             tracing::debug!("disconnected");
+            self.record_awake(host_name, connection.connected_at.elapsed());
             let _ = self.changed.send(());
         }
     }
 
-    pub fn get_connected(&self) -> Vec<&BuildMachine> {
-        let mut last_seen = self.last_seen.lock().unwrap();
+    pub fn get_connected(&self) -> Vec<BuildMachine> {
+        let all_builders = self.all_builders();
+        let mut connections = self.connections.lock().unwrap();
 
         let mut builders = Vec::new();
-        for (host_name, builder) in &self.builders {
-            if let Some(at) = last_seen.get(host_name) {
-                let elapsed = at.elapsed();
+        let mut stale = Vec::new();
+        for builder in all_builders {
+            let host_name = builder.host_name();
+            if let Some(connection) = connections.get(host_name) {
+                let elapsed = connection.last_seen.elapsed();
                 if elapsed > self.stale_after {
                     tracing::info!("removing stale builder: {host_name}, not seen for {elapsed:?}");
-                    last_seen.remove(host_name);
+                    let connection = connections.remove(host_name).unwrap();
+                    stale.push((host_name.to_string(), connection));
                 } else {
                     builders.push(builder);
                 }
             }
         }
+        drop(connections);
+
+        for (host_name, connection) in stale {
+            let awake = connection.last_seen.duration_since(connection.connected_at);
+            self.record_awake(&host_name, awake);
+        }
 
         builders
     }
 
-    pub fn update_queued(&self, queued: impl IntoIterator<Item = System>) {
-        let updated = queued.into_iter().collect();
-        let mut current = self.queued_systems.lock().unwrap();
+    fn record_awake(&self, host_name: &str, awake: Duration) {
+        let today = Local::now().date_naive();
+        let mut recorded = self.awake.lock().unwrap();
+        let (date, total) = recorded
+            .entry(host_name.to_string())
+            .or_insert((today, Duration::ZERO));
+        if *date != today {
+            *date = today;
+            *total = Duration::ZERO;
+        }
+        *total += awake;
+    }
+
+    /// How long the builder has been connected today, counting the current connection
+    fn awake_today(&self, host_name: &str, now: Instant) -> Duration {
+        let today = Local::now().date_naive();
+        let recorded = self
+            .awake
+            .lock()
+            .unwrap()
+            .get(host_name)
+            .filter(|(date, _)| *date == today)
+            .map(|(_, total)| *total)
+            .unwrap_or_default();
+        let current = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(host_name)
+            .map(|connection| now.saturating_duration_since(connection.connected_at))
+            .unwrap_or_default();
+        recorded + current
+    }
+
+    /// Connected builders, and offline builders that may be woken now
+    fn available_builders(&self, now: Instant) -> (Vec<BuildMachine>, Vec<BuildMachine>) {
+        let connected = self.get_connected();
+        let connected_host_names = connected
+            .iter()
+            .map(|b| b.host_name())
+            .collect::<HashSet<_>>();
+        let time = Local::now().time();
+        let unreachable = self
+            .waking
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| state.unreachable)
+            .map(|(host_name, _)| host_name.clone())
+            .collect::<HashSet<_>>();
+        let wakeable = self
+            .all_builders()
+            .into_iter()
+            .filter(|builder| {
+                builder.wake_method().is_some()
+                    && !connected_host_names.contains(builder.host_name())
+                    && !unreachable.contains(builder.host_name())
+                    && builder
+                        .policy
+                        .may_wake(time, self.awake_today(builder.host_name(), now))
+            })
+            .collect();
+        (connected, wakeable)
+    }
+
+    pub fn update_queued(&self, queued: impl IntoIterator<Item = QueuedBuild>) {
+        let mut updated = HashMap::new();
+        for build in queued {
+            *updated.entry(build).or_default() += 1;
+        }
+        let mut current = self.queued.lock().unwrap();
         if *current != updated {
             *current = updated;
-            tracing::info!("Queue updated: systems = {:?}", *current);
+            let mut builds = current
+                .iter()
+                .map(|(build, count)| format!("{count}x {build}"))
+                .collect::<Vec<_>>();
+            builds.sort();
+            tracing::info!("Queue updated: {builds:?}");
             let _ = self.changed.send(());
         } else {
             tracing::debug!("Queue unchanged");
         }
     }
 
-    pub fn machines_to_wake(&self) -> Vec<MacAddress> {
-        let queued_systems = self.queued_systems.lock().unwrap().clone();
-        let connected = self
-            .get_connected()
-            .iter()
-            .map(|b| b.host_name())
-            .collect::<HashSet<_>>();
+    /// Record the builders that build steps are running on
+    pub fn update_running<'a>(&self, host_names: impl IntoIterator<Item = &'a str>) {
+        let mut updated = HashMap::new();
+        for host_name in host_names {
+            *updated.entry(host_name.to_string()).or_default() += 1;
+        }
+        let mut current = self.running.lock().unwrap();
+        if *current != updated {
+            *current = updated;
+            tracing::info!("Running steps updated: {:?}", *current);
+            let _ = self.changed.send(());
+        }
+    }
+
+    /// Remember the kinds of builds each project needs
+    pub fn record_projects(&self, projects: HashMap<String, HashSet<QueuedBuild>>) {
+        let mut history = self.history.lock().unwrap();
+        for (project, builds) in projects {
+            history.entry(project).or_default().extend(builds);
+        }
+    }
 
-        self.builders
+    /// Kinds of builds any project needed before
+    pub fn recorded_builds(&self) -> HashSet<QueuedBuild> {
+        self.history
+            .lock()
+            .unwrap()
             .values()
-            .filter_map(|builder| {
-                let mac_address = builder.mac_address()?;
-                if !connected.contains(builder.host_name())
-                    && queued_systems
-                        .intersection(&builder.systems())
-                        .next()
-                        .is_some()
-                {
-                    Some(mac_address)
-                } else {
-                    None
-                }
-            })
+            .flatten()
+            .cloned()
             .collect()
     }
-}
 
-pub struct BuilderHandle {
-    store: Arc<Store>,
-    builder: BuildMachine,
-}
-
-impl BuilderHandle {
-    pub fn wanted(&self) -> bool {
-        let queued = self.store.queued_systems.lock().unwrap();
-        queued
-            .intersection(&self.builder.systems())
-            .next()
-            .is_some()
+    /// Expect the kinds of builds `project` needed before to be queued until `until`, e.g. because
+    /// a push will be evaluated. Returns the expected kinds.
+    pub fn expect_builds(&self, project: &str, until: Instant) -> Vec<QueuedBuild> {
+        let builds = self
+            .history
+            .lock()
+            .unwrap()
+            .get(project)
+            .map(|builds| builds.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if builds.is_empty() {
+            return builds;
+        }
+        let mut expected = self.expected.lock().unwrap();
+        for build in &builds {
+            let expected_until = expected.entry(build.clone()).or_insert(until);
+            *expected_until = until.max(*expected_until);
+        }
+        let _ = self.changed.send(());
+        builds
     }
 
-    pub fn heartbeat(&self, now: Instant) -> Result<(), AppError> {
-        let mut last_seen = self.store.last_seen.lock().unwrap();
-        let Some(at) = last_seen.get_mut(self.builder.host_name()) else {
-            return Err(AppError::from((
-                StatusCode::BAD_REQUEST,
-                "{host_name} connection stale",
-            )));
-        };
-        *at = now;
-        Ok(())
+    /// Kinds of builds expected but not queued yet
+    fn expected(&self, now: Instant) -> Vec<QueuedBuild> {
+        let mut expected = self.expected.lock().unwrap();
+        expected.retain(|_, until| now < *until);
+        expected.keys().cloned().collect()
     }
-}
 
-impl Drop for BuilderHandle {
-    fn drop(&mut self) {
-        self.store.disconnect(self.builder.host_name())
+    /// Offline builders to wake so that connected capacity covers the queue, and the builds
+    /// expected soon
+    pub fn machines_to_wake(&self, now: Instant) -> Vec<BuildMachine> {
+        let mut queued = self.queued.lock().unwrap().clone();
+        for build in self.expected(now) {
+            queued.entry(build).or_insert(1);
+        }
+        let running = self.running.lock().unwrap().clone();
+        let (connected, wakeable) = self.available_builders(now);
+
+        scheduler::machines_to_wake(&queued, &running, &connected, &wakeable)
+            .into_iter()
+            .cloned()
+            .collect()
     }
-}
 
-#[tracing::instrument(skip_all)]
-pub async fn wake_builders(store: Arc<Store>) -> anyhow::Result<Infallible> {
-    let mut sub = store.subscribe();
-    loop {
-        tokio::select! {
-            r = sub.changed() => r?,
-            _ = tokio::time::sleep(Duration::from_secs(30)) => {},
+    /// Attempts to wake builders that are due, spacing out retries for builders that don't
+    /// connect and escalating to their fallback. The attempts are recorded, so that the builders
+    /// are kept awake for a while once they connect.
+    pub fn wake_attempts(&self, now: Instant) -> Vec<WakeAttempt> {
+        let to_wake = self.machines_to_wake(now);
+        let mut waking = self.waking.lock().unwrap();
+
+        // builders that are no longer needed start over the next time
+        for (host_name, state) in waking.iter_mut() {
+            let stale = state
+                .last_attempt
+                .is_some_and(|at| now.saturating_duration_since(at) >= WAKING_FOR);
+            if stale && !state.unreachable && !to_wake.iter().any(|b| b.host_name() == host_name) {
+                *state = WakeState {
+                    time_to_connect: state.time_to_connect,
+                    ..WakeState::default()
+                };
+            }
         }
 
-        let mac_addresses = store.machines_to_wake();
-        if mac_addresses.is_empty() {
-            continue;
+        let mut attempts = Vec::new();
+        for builder in to_wake {
+            let state = waking.entry(builder.host_name().to_string()).or_default();
+            if state
+                .last_attempt
+                .is_some_and(|at| now.saturating_duration_since(at) < self.retry_delay(state))
+            {
+                continue;
+            }
+            if self
+                .wake_retries
+                .give_up_after
+                .is_some_and(|give_up_after| state.attempts >= give_up_after)
+            {
+                tracing::warn!(
+                    "{} didn't connect after {} attempts to wake it, considering it unreachable",
+                    builder.host_name(),
+                    state.attempts
+                );
+                state.unreachable = true;
+                let _ = self.changed.send(());
+                continue;
+            }
+
+            let escalate = state.failed || state.attempts >= self.wake_retries.escalate_after;
+            let Some(method) = builder
+                .wake_fallback
+                .as_ref()
+                .filter(|_| escalate)
+                .or(builder.wake_method())
+                .cloned()
+            else {
+                continue;
+            };
+            state.first_attempt.get_or_insert(now);
+            state.last_attempt = Some(now);
+            state.attempts += 1;
+            attempts.push(WakeAttempt {
+                attempt: state.attempts,
+                method,
+                builder,
+            });
         }
-        if let Err(err) = wake_all(&mac_addresses).await {
-            tracing::error!(?err, "Failed to open socket");
-        };
+        attempts
     }
-}
 
-async fn wake_all(mac_addresses: &[MacAddress]) -> anyhow::Result<()> {
-    let from_addr = (Ipv4Addr::new(0, 0, 0, 0), 0);
-    let socket = UdpSocket::bind(from_addr).await?;
-    socket.set_broadcast(true)?;
-
-    // TODO: parallel?
-    for mac_address in mac_addresses {
-        wake(&socket, *mac_address).await;
+    /// How long to wait for a builder to connect before trying to wake it again
+    fn retry_delay(&self, state: &WakeState) -> Duration {
+        let backoff = self
+            .wake_retries
+            .retry_interval
+            .saturating_mul(2u32.saturating_pow(state.attempts.saturating_sub(1)))
+            .min(self.wake_retries.max_retry_interval);
+        backoff.max(state.time_to_connect.unwrap_or_default())
     }
 
-    Ok(())
-}
+    /// Record that an attempt to wake a builder failed, so the next one escalates
+    pub fn wake_failed(&self, host_name: &str) {
+        if let Some(state) = self.waking.lock().unwrap().get_mut(host_name) {
+            state.failed = true;
+        }
+    }
 
-#[tracing::instrument(skip_all, fields(%mac_address))]
-pub async fn wake(socket: &UdpSocket, mac_address: MacAddress) {
-    let to_addr = (Ipv4Addr::new(255, 255, 255, 255), 9);
-    let packet = wake_on_lan::MagicPacket::new(mac_address.as_ref());
-    match socket.send_to(packet.magic_bytes(), to_addr).await {
-        Ok(_) => tracing::debug!("Sent WOL packet"),
-        Err(err) => tracing::error!(?err, "Failed to send WOL packet"),
+    /// Ask a connected builder on the network of `builder` to send its wake-on-lan packet,
+    /// returning the relaying builder's host name
+    pub fn relay_wake(&self, builder: &BuildMachine) -> Option<String> {
+        let mac_address = builder.mac_address()?;
+        let connections = self.connections.lock().unwrap();
+        let (host_name, (_, relay)) = match &builder.wake_via {
+            Some(wake_via) => connections
+                .get_key_value(wake_via)
+                .and_then(|(host_name, connection)| Some((host_name, connection.relay.as_ref()?))),
+            None => {
+                let network = builder.wake_on_lan.network()?;
+                connections.iter().find_map(|(host_name, connection)| {
+                    let relay = connection.relay.as_ref()?;
+                    network.contains(&relay.0).then_some((host_name, relay))
+                })
+            }
+        }?;
+        relay
+            .send(ServerMessage::WakeOnLan {
+                mac_address: *mac_address.as_ref(),
+                secure_on: builder
+                    .wake_on_lan
+                    .secure_on
+                    .as_ref()
+                    .map(|password| password.as_ref().to_vec()),
+                to: builder.wake_on_lan.target(),
+            })
+            .ok()?;
+        Some(host_name.clone())
     }
 }
 
-#[tracing::instrument(skip_all)]
-pub async fn watch_job_queue(
+pub struct BuilderHandle {
     store: Arc<Store>,
-    client: HydraClient,
-) -> Result<Infallible, RecvError> {
-    let mut interval = tokio::time::interval(Duration::from_secs(15));
-    loop {
-        interval.tick().await;
+    host_name: String,
+}
 
-        let builds = match client.get_queue().await {
-            Ok(builds) => builds,
-            Err(err) => {
-                tracing::warn!(?err, "Failed to poll queue");
-                continue;
+impl BuilderHandle {
+    /// Why the builder should stay awake, if it should
+    pub fn wanted(&self, now: Instant) -> Option<&'static str> {
+        // never interrupt a running build
+        if self
+            .store
+            .running
+            .lock()
+            .unwrap()
+            .contains_key(&self.host_name)
+        {
+            return Some("Builds running");
+        }
+
+        let builder = self.store.builder(&self.host_name)?;
+        let policy = &builder.policy;
+        if policy.exhausted(self.store.awake_today(&self.host_name, now)) {
+            return None;
+        }
+
+        let (connected_at, woken_at, last_wanted) = {
+            let connections = self.store.connections.lock().unwrap();
+            let connection = connections.get(&self.host_name)?;
+            (
+                connection.connected_at,
+                connection.woken_at,
+                connection.last_wanted,
+            )
+        };
+        let since = |at: Instant| now.saturating_duration_since(at);
+        if policy
+            .min_awake
+            .is_some_and(|min_awake| since(connected_at) < min_awake)
+        {
+            return Some("Minimum awake time");
+        }
+        let min_awake_after_wake = policy
+            .min_awake_after_wake
+            .unwrap_or(self.store.keep_awake.min_awake_after_wake);
+        if woken_at.is_some_and(|woken_at| since(woken_at) < min_awake_after_wake) {
+            return Some("Recently woken");
+        }
+
+        // last resort builders are only wanted for builds nothing else can run
+        let others = if policy.last_resort {
+            let (connected, wakeable) = self.store.available_builders(now);
+            connected
+                .into_iter()
+                .chain(wakeable)
+                .filter(|other| other.host_name() != self.host_name && !other.policy.last_resort)
+                .collect()
+        } else {
+            vec![]
+        };
+        let queued = self.store.queued.lock().unwrap().keys().any(|build| {
+            builder.can_build(build) && !others.iter().any(|other| other.can_build(build))
+        });
+        if queued {
+            if let Some(connection) = self
+                .store
+                .connections
+                .lock()
+                .unwrap()
+                .get_mut(&self.host_name)
+            {
+                connection.last_wanted = Some(now);
             }
+            return Some("Builds queued");
+        }
+        if self
+            .store
+            .expected(now)
+            .iter()
+            .any(|build| builder.can_build(build))
+        {
+            return Some("Builds expected");
+        }
+
+        let idle_grace_period = policy
+            .idle_grace_period
+            .unwrap_or(self.store.keep_awake.idle_grace_period);
+        last_wanted
+            .is_some_and(|last_wanted| since(last_wanted) < idle_grace_period)
+            .then_some("Idle grace period")
+    }
+
+    /// How to power the builder down, once it's been idle long enough according to its policy.
+    /// Only returned once per connection.
+    pub fn power_down(&self, now: Instant, wanted: bool) -> Option<PowerDown> {
+        let power_down = self.store.builder(&self.host_name)?.policy.power_down?;
+        let mut connections = self.store.connections.lock().unwrap();
+        let connection = connections.get_mut(&self.host_name)?;
+        if wanted {
+            connection.idle_since = None;
+            return None;
+        }
+        let idle_since = *connection.idle_since.get_or_insert(now);
+        if connection.powering_down || now.saturating_duration_since(idle_since) < power_down.after
+        {
+            return None;
+        }
+        connection.powering_down = true;
+        Some(power_down)
+    }
+
+    /// Accept messages to relay to the builder, which is connected from `addr`
+    pub fn relay(&self, addr: IpAddr) -> mpsc::UnboundedReceiver<ServerMessage> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if let Some(connection) = self
+            .store
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(&self.host_name)
+        {
+            connection.relay = Some((addr, sender));
+        }
+        receiver
+    }
+
+    pub fn heartbeat(&self, now: Instant) -> Result<(), AppError> {
+        let mut connections = self.store.connections.lock().unwrap();
+        let Some(connection) = connections.get_mut(&self.host_name) else {
+            return Err(AppError::from((
+                StatusCode::BAD_REQUEST,
+                format!("{} connection stale", self.host_name),
+            )));
         };
+        connection.last_seen = now;
+        Ok(())
+    }
+}
 
-        // TODO: log
-        store.update_queued(builds.into_iter().map(|b| b.system));
+impl Drop for BuilderHandle {
+    fn drop(&mut self) {
+        self.store.disconnect(&self.host_name)
     }
 }
 
@@ -271,9 +856,9 @@ pub async fn generate_machines_file(
             .read_to_string(&mut current)
             .await?;
 
-        let mut updated = store
-            .get_connected()
-            .into_iter()
+        let connected = store.get_connected();
+        let mut updated = connected
+            .iter()
             .flat_map(|builder| iter::once(&builder.spec).chain(builder.vms.iter()))
             .map(|spec| format!("{}\n", spec))
             .collect::<Vec<_>>();
@@ -292,37 +877,45 @@ pub async fn generate_machines_file(
 
         tokio::select! {
             r = sub.changed() => r?,
-            _ = tokio::time::sleep(Duration::from_secs(30)) => {},
+            _ = tokio::time::sleep(store.reevaluate_interval()) => {},
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::model::BuildMachineSpec;
-
     use super::*;
+    use crate::model::System;
+    use secrecy::SecretString;
+
+    fn store(builders: impl IntoIterator<Item = BuildMachine>) -> Arc<Store> {
+        Arc::new(Store::new(builders, StoreConfig::default()))
+    }
+
+    /// A builder woken with wake-on-lan
+    fn wakeable(spec: BuildMachineSpec, mac_address: &str) -> BuildMachine {
+        let mut builder = BuildMachine::from(spec);
+        builder.mac_address = Some(serde_json::from_value(mac_address.into()).unwrap());
+        builder
+    }
+
+    fn spec(host_name: &str) -> BuildMachineSpec {
+        BuildMachineSpec {
+            ssh_user: None,
+            host_name: host_name.into(),
+            ssh_key: None,
+            systems: [System::X86_64Linux].into(),
+            supported_features: Default::default(),
+            mandatory_features: Default::default(),
+            max_jobs: None,
+            speed_factor: None,
+            public_host_key: None,
+        }
+    }
 
     #[test]
     fn subscribe() {
-        let store = Arc::new(Store::new(
-            Duration::from_secs(60),
-            vec![BuildMachine {
-                spec: BuildMachineSpec {
-                    ssh_user: None,
-                    host_name: "bogus".into(),
-                    ssh_key: None,
-                    systems: [System::X86_64Linux].into(),
-                    supported_features: Default::default(),
-                    mandatory_features: Default::default(),
-                    max_jobs: None,
-                    speed_factor: None,
-                    public_host_key: None,
-                },
-                vms: vec![],
-                mac_address: None,
-            }],
-        ));
+        let store = store(vec![BuildMachine::from(spec("bogus"))]);
 
         let mut sub = store.subscribe();
         assert!(!sub.has_changed().unwrap());
@@ -335,4 +928,319 @@ mod tests {
         drop(handle);
         assert!(sub.has_changed().unwrap());
     }
+
+    #[test]
+    fn register() {
+        let store = Arc::new(Store::new(
+            vec![],
+            StoreConfig {
+                registration: Registration::AllowList(["bogus".to_string()].into()),
+                ..StoreConfig::default()
+            },
+        ));
+
+        assert!(store.connect("bogus", Instant::now()).is_err());
+        assert!(store.register(spec("other"), None).is_err());
+
+        let mut reported = spec("bogus");
+        reported.ssh_key = Some("/etc/ssh/ssh_host_ed25519_key".to_string());
+        let _handle = store
+            .connect_registered(reported, None, Instant::now())
+            .unwrap();
+        assert_eq!(store.get_connected().len(), 1);
+        assert_eq!(store.builder("bogus").unwrap().spec.ssh_key, None);
+    }
+
+    #[test]
+    fn register_on_every_connection() {
+        let store = Arc::new(Store::new(
+            vec![],
+            StoreConfig {
+                registration: Registration::Token(SecretString::from("hocus pocus")),
+                ..StoreConfig::default()
+            },
+        ));
+
+        let handle = store
+            .connect_registered(spec("bogus"), Some("hocus pocus"), Instant::now())
+            .unwrap();
+        drop(handle);
+
+        // known by now, but still has to present the token
+        assert!(store.connect("bogus", Instant::now()).is_err());
+        assert!(
+            store
+                .connect_registered(spec("bogus"), None, Instant::now())
+                .is_err()
+        );
+        assert!(
+            store
+                .connect_registered(spec("bogus"), Some("hocus pocus"), Instant::now())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn merge_reported_spec() {
+        let mut configured = spec("bogus");
+        configured.systems.clear();
+        configured.max_jobs = Some(2);
+        let store = store(vec![
+            BuildMachine::from(configured),
+            BuildMachine::from(spec("other")),
+        ]);
+        assert!(!store.features_differ());
+
+        let mut reported = spec("bogus");
+        reported.max_jobs = Some(16);
+        reported.supported_features = ["kvm".to_string()].into();
+        store.register(reported, None).unwrap();
+
+        let builder = store.builder("bogus").unwrap();
+        assert_eq!(builder.spec.systems, [System::X86_64Linux].into());
+        assert_eq!(builder.spec.max_jobs, Some(2));
+        assert_eq!(builder.spec.supported_features, ["kvm".to_string()].into());
+        assert!(store.features_differ());
+    }
+
+    #[test]
+    fn wake_by_features() {
+        let builder = |host_name: &str, mac_address: &str, features: &[&str]| {
+            let mut spec = spec(host_name);
+            spec.supported_features = features.iter().map(|f| f.to_string()).collect();
+            wakeable(spec, mac_address)
+        };
+        let store = store(vec![
+            builder("bogus", "00:00:00:00:00:01", &[]),
+            builder("other", "00:00:00:00:00:02", &["kvm"]),
+        ]);
+
+        store.update_queued([QueuedBuild {
+            system: System::X86_64Linux,
+            required_features: ["kvm".to_string()].into(),
+        }]);
+        let wake = store.machines_to_wake(Instant::now());
+        assert_eq!(wake.len(), 1);
+        assert_eq!(wake[0].host_name(), "other");
+
+        // one queued build only needs one builder
+        store.update_queued([QueuedBuild::from(System::X86_64Linux)]);
+        assert_eq!(store.machines_to_wake(Instant::now()).len(), 1);
+        store.update_queued([
+            QueuedBuild::from(System::X86_64Linux),
+            QueuedBuild::from(System::X86_64Linux),
+        ]);
+        assert_eq!(store.machines_to_wake(Instant::now()).len(), 2);
+
+        store.update_queued([QueuedBuild::from(System::Aarch64Linux)]);
+        assert!(store.machines_to_wake(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn wake_for_expected_builds() {
+        let builder = wakeable(spec("bogus"), "00:00:00:00:00:01");
+        let store = store(vec![builder]);
+        let now = Instant::now();
+        let until = now + Duration::from_secs(20 * 60);
+
+        // nothing is known about the project yet
+        assert!(store.expect_builds("nix-config", until).is_empty());
+
+        store.record_projects(
+            [(
+                "nix-config".to_string(),
+                [QueuedBuild::from(System::X86_64Linux)].into(),
+            )]
+            .into(),
+        );
+        assert_eq!(store.expect_builds("nix-config", until).len(), 1);
+        assert_eq!(store.machines_to_wake(now).len(), 1);
+
+        let handle = store.connect("bogus", now).unwrap();
+        let after_wake = now + KeepAwakeConfig::default().min_awake_after_wake;
+        assert_eq!(handle.wanted(after_wake), Some("Builds expected"));
+        assert_eq!(handle.wanted(until), None);
+    }
+
+    #[test]
+    fn keep_awake() {
+        let builder = wakeable(spec("bogus"), "00:00:00:00:00:01");
+        let store = Arc::new(Store::new(
+            vec![builder],
+            StoreConfig {
+                keep_awake: KeepAwakeConfig {
+                    idle_grace_period: Duration::from_secs(5 * 60),
+                    min_awake_after_wake: Duration::from_secs(10 * 60),
+                },
+                ..StoreConfig::default()
+            },
+        ));
+        let start = Instant::now();
+        let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+
+        store.update_queued([QueuedBuild::from(System::X86_64Linux)]);
+        assert_eq!(store.wake_attempts(start).len(), 1);
+        let handle = store.connect("bogus", minutes(1)).unwrap();
+        assert_eq!(handle.wanted(minutes(1)), Some("Recently woken"));
+
+        store.update_queued([]);
+        assert_eq!(handle.wanted(minutes(2)), Some("Recently woken"));
+        assert_eq!(handle.wanted(minutes(10)), None);
+
+        store.update_queued([QueuedBuild::from(System::X86_64Linux)]);
+        assert_eq!(handle.wanted(minutes(10)), Some("Builds queued"));
+        store.update_queued([]);
+        assert_eq!(handle.wanted(minutes(14)), Some("Idle grace period"));
+        assert_eq!(handle.wanted(minutes(16)), None);
+    }
+
+    #[test]
+    fn wake_retries() {
+        let mut builder = wakeable(spec("bogus"), "00:00:00:00:00:01");
+        let fallback = WakeMethod::Command {
+            command: vec!["ipmitool".to_string()],
+        };
+        builder.wake_fallback = Some(fallback.clone());
+        let store = Arc::new(Store::new(
+            vec![builder],
+            StoreConfig {
+                wake_retries: WakeRetryConfig {
+                    retry_interval: Duration::from_secs(60),
+                    max_retry_interval: Duration::from_secs(4 * 60),
+                    escalate_after: 2,
+                    give_up_after: Some(4),
+                    ..WakeRetryConfig::default()
+                },
+                ..StoreConfig::default()
+            },
+        ));
+        let start = Instant::now();
+        let seconds = |seconds: u64| start + Duration::from_secs(seconds);
+        let methods = |now: Instant| {
+            store
+                .wake_attempts(now)
+                .into_iter()
+                .map(|attempt| (attempt.attempt, attempt.method))
+                .collect::<Vec<_>>()
+        };
+
+        store.update_queued([QueuedBuild::from(System::X86_64Linux)]);
+        assert_eq!(methods(start), [(1, WakeMethod::WakeOnLan)]);
+        assert!(store.is_waking(seconds(30)));
+        assert!(methods(seconds(30)).is_empty());
+        assert_eq!(methods(seconds(60)), [(2, WakeMethod::WakeOnLan)]);
+        assert!(methods(seconds(150)).is_empty());
+        assert_eq!(methods(seconds(180)), [(3, fallback.clone())]);
+        assert_eq!(methods(seconds(420)), [(4, fallback.clone())]);
+
+        // given up on until it connects by itself
+        assert!(methods(seconds(660)).is_empty());
+        assert!(!store.is_waking(seconds(660)));
+        assert!(store.machines_to_wake(seconds(660)).is_empty());
+
+        let handle = store.connect("bogus", seconds(720)).unwrap();
+        drop(handle);
+        assert_eq!(methods(seconds(780)), [(1, WakeMethod::WakeOnLan)]);
+
+        // a failed attempt escalates right away, and retries wait as long as it took to connect
+        store.wake_failed("bogus");
+        assert!(methods(seconds(800)).is_empty());
+        assert_eq!(methods(seconds(1080)), [(2, fallback)]);
+    }
+
+    #[test]
+    fn relay_wake_on_lan() {
+        let store = store(vec![BuildMachine::from(spec("bogus"))]);
+        let target = |broadcast_address: &str, wake_via: Option<&str>| {
+            let mut builder = wakeable(spec("other"), "00:00:00:00:00:02");
+            builder.wake_on_lan.broadcast_address = broadcast_address.parse().unwrap();
+            builder.wake_via = wake_via.map(str::to_string);
+            builder
+        };
+
+        let handle = store.connect("bogus", Instant::now()).unwrap();
+        assert_eq!(store.relay_wake(&target("192.168.2.255", None)), None);
+        let mut relayed = handle.relay("192.168.2.10".parse().unwrap());
+
+        // inferred from the subnet
+        assert_eq!(
+            store.relay_wake(&target("192.168.2.255", None)).as_deref(),
+            Some("bogus")
+        );
+        let Ok(ServerMessage::WakeOnLan {
+            mac_address,
+            secure_on,
+            to,
+        }) = relayed.try_recv()
+        else {
+            panic!("expected a wake-on-lan message");
+        };
+        assert_eq!(mac_address, [0, 0, 0, 0, 0, 2]);
+        assert_eq!(secure_on, None);
+        assert_eq!(to.to_string(), "192.168.2.255:9");
+
+        assert_eq!(store.relay_wake(&target("192.168.3.255", None)), None);
+        assert_eq!(
+            store
+                .relay_wake(&target("255.255.255.255", Some("bogus")))
+                .as_deref(),
+            Some("bogus")
+        );
+        assert_eq!(
+            store.relay_wake(&target("255.255.255.255", Some("missing"))),
+            None
+        );
+    }
+
+    #[test]
+    fn keep_running_builders_awake() {
+        let mut bogus = spec("bogus");
+        bogus.max_jobs = Some(1);
+        let other = wakeable(spec("other"), "00:00:00:00:00:02");
+        let store = Arc::new(Store::new(
+            vec![BuildMachine::from(bogus), other],
+            StoreConfig {
+                keep_awake: KeepAwakeConfig {
+                    idle_grace_period: Duration::ZERO,
+                    min_awake_after_wake: Duration::ZERO,
+                },
+                ..StoreConfig::default()
+            },
+        ));
+        let handle = store.connect("bogus", Instant::now()).unwrap();
+        assert_eq!(handle.wanted(Instant::now()), None);
+
+        store.update_running(["bogus"]);
+        assert_eq!(handle.wanted(Instant::now()), Some("Builds running"));
+
+        // bogus is busy, so queued builds need another builder
+        store.update_queued([QueuedBuild::from(System::X86_64Linux)]);
+        assert_eq!(store.machines_to_wake(Instant::now()).len(), 1);
+
+        store.update_running([]);
+        assert_eq!(handle.wanted(Instant::now()), Some("Builds queued"));
+        assert!(store.machines_to_wake(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn power_down_idle_builders() {
+        let mut builder = BuildMachine::from(spec("bogus"));
+        builder.policy.power_down = Some(PowerDown {
+            action: hydra_sentinel::PowerAction::Suspend,
+            after: Duration::from_secs(10 * 60),
+        });
+        let store = store(vec![builder]);
+        let start = Instant::now();
+        let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+
+        let handle = store.connect("bogus", start).unwrap();
+        assert!(handle.power_down(minutes(0), false).is_none());
+        assert!(handle.power_down(minutes(5), true).is_none());
+        // idle time starts over once the builder isn't wanted anymore
+        assert!(handle.power_down(minutes(6), false).is_none());
+        assert!(handle.power_down(minutes(15), false).is_none());
+        let power_down = handle.power_down(minutes(16), false).unwrap();
+        assert_eq!(power_down.action, hydra_sentinel::PowerAction::Suspend);
+        assert!(handle.power_down(minutes(17), false).is_none());
+    }
 }
@@ -1,15 +1,21 @@
+use super::auth::authenticate;
 use super::store::{BuilderHandle, Store};
-use crate::error::AppError;
+use crate::{error::AppError, listener::Peer};
 use axum::extract::connect_info::ConnectInfo;
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code};
 use axum::extract::{Query, State};
+use axum::http::HeaderMap;
 use axum::response::IntoResponse;
 use futures_util::{sink::SinkExt, stream::StreamExt};
-use hydra_sentinel::SentinelMessage;
+use hydra_sentinel::{ClientMessage, PROTOCOL_VERSION, ServerMessage};
+use reqwest::StatusCode;
 use serde::Deserialize;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a builder has to complete the handshake (and registration, if unknown) after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Deserialize)]
 pub struct Params {
@@ -20,19 +26,149 @@ pub async fn connect(
     ws: WebSocketUpgrade,
     State(store): State<Arc<Store>>,
     Query(Params { host_name }): Query<Params>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, AppError> {
-    let handle = store.connect(&host_name, Instant::now())?;
+    let addr = peer.addr;
+    peer.verify_certificate(&host_name)?;
+    if let Some(token_file) = store.token_file(&host_name) {
+        authenticate(
+            &token_file,
+            &host_name,
+            &headers,
+            store.seen_signatures(),
+            SystemTime::now(),
+        )
+        .await?;
+    }
+
+    if !store.is_configured(&host_name) && !store.admits(&host_name) {
+        return Err(AppError::from((
+            StatusCode::BAD_REQUEST,
+            format!("Unknown builder: {host_name}"),
+        )));
+    }
 
     tracing::info!("{host_name:?}@{addr} connected");
-    Ok(ws.on_upgrade(move |socket| async move {
+    Ok(ws.on_upgrade(move |mut socket| async move {
+        let handle = match handshake(&store, &host_name, &mut socket).await {
+            Ok(handle) => handle,
+            Err(err) => {
+                tracing::warn!(%err, "{host_name:?}@{addr} failed handshake");
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: err.to_string().into(),
+                    })))
+                    .await;
+                return;
+            }
+        };
+
         match handle_socket(store, &host_name, addr, socket, Arc::new(handle)).await {
-            Ok(()) => tracing::info!("{host_name:?}@{addr} connected"),
+            Ok(()) => tracing::info!("{host_name:?}@{addr} disconnected"),
             Err(err) => tracing::error!(?err, "{host_name:?}@{addr} disconnected"),
         }
     }))
 }
 
+fn text(msg: ServerMessage) -> Message {
+    Message::text::<String>(msg.into())
+}
+
+async fn recv_message(socket: &mut WebSocket) -> Result<ClientMessage, AppError> {
+    let msg = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        while let Some(msg) = socket.recv().await {
+            if let Message::Text(msg) = msg? {
+                return Ok(msg);
+            }
+        }
+        Err(AppError::from((
+            StatusCode::BAD_REQUEST,
+            "Connection closed during handshake",
+        )))
+    })
+    .await
+    .map_err(|_| {
+        AppError::from((
+            StatusCode::REQUEST_TIMEOUT,
+            format!("Timed out waiting for hello (protocol v{PROTOCOL_VERSION}), upgrade client"),
+        ))
+    })??;
+
+    ClientMessage::try_from(msg.as_str())
+        .map_err(|err| AppError::from((StatusCode::BAD_REQUEST, format!("Invalid message: {err}"))))
+}
+
+/// Negotiate the protocol version, then wait for builders that aren't configured to register.
+/// Builders are only considered connected once they've completed the handshake.
+async fn handshake(
+    store: &Arc<Store>,
+    host_name: &str,
+    socket: &mut WebSocket,
+) -> Result<BuilderHandle, AppError> {
+    match recv_message(socket).await? {
+        ClientMessage::Hello {
+            protocol_version,
+            client_version,
+        } => {
+            if protocol_version != PROTOCOL_VERSION {
+                let reason = format!(
+                    "Unsupported protocol v{protocol_version} (client {client_version}), server requires v{PROTOCOL_VERSION}"
+                );
+                socket
+                    .send(text(ServerMessage::Reject {
+                        reason: reason.clone(),
+                    }))
+                    .await?;
+                return Err(AppError::from((StatusCode::BAD_REQUEST, reason)));
+            }
+            tracing::debug!("{host_name} running client {client_version}");
+        }
+        msg => {
+            return Err(AppError::from((
+                StatusCode::BAD_REQUEST,
+                format!("Expected hello, got {msg:?}"),
+            )));
+        }
+    }
+
+    socket
+        .send(text(ServerMessage::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+        .await?;
+    socket
+        .send(text(ServerMessage::Config {
+            heartbeat_interval_secs: store.heartbeat_interval().as_secs().max(1),
+        }))
+        .await?;
+
+    if store.is_configured(host_name) {
+        return store.connect(host_name, Instant::now());
+    }
+
+    match recv_message(socket).await? {
+        ClientMessage::Register { spec, token } => {
+            if spec.host_name != host_name {
+                return Err(AppError::from((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Registered host name {:?} does not match {host_name:?}",
+                        spec.host_name
+                    ),
+                )));
+            }
+            store.connect_registered(spec, token.as_deref(), Instant::now())
+        }
+        msg => Err(AppError::from((
+            StatusCode::BAD_REQUEST,
+            format!("Expected registration, got {msg:?}"),
+        ))),
+    }
+}
+
 #[tracing::instrument(skip_all, fields(%host_name, %who))]
 async fn handle_socket(
     store: Arc<Store>,
@@ -46,35 +182,85 @@ async fn handle_socket(
     sender.send(Message::Ping(Default::default())).await?;
 
     // TODO: throttle
+    let recv_store = store.clone();
     let send_handle = handle.clone();
+    let mut relay = handle.relay(who.ip());
     let send_task = async move {
         let mut sub = store.subscribe();
         loop {
-            let wanted = send_handle.wanted();
-            if wanted {
-                tracing::info!("requesting builder stay awake");
+            if store.is_shutting_down() {
+                sender
+                    .send(text(ServerMessage::Shutdown {
+                        reason: Some("Server shutting down".to_string()),
+                    }))
+                    .await?;
+                sender.send(Message::Close(None)).await?;
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            let reason = send_handle.wanted(now);
+            if let Some(reason) = reason {
+                tracing::info!("requesting builder stay awake: {reason}");
             }
             sender
-                .send(Message::text::<String>(
-                    SentinelMessage::KeepAwake(wanted).into(),
-                ))
+                .send(text(ServerMessage::KeepAwake {
+                    awake: reason.is_some(),
+                    reason: reason.map(str::to_string),
+                }))
                 .await?;
+            if let Some(power_down) = send_handle.power_down(now, reason.is_some()) {
+                tracing::info!("requesting builder power down: {:?}", power_down.action);
+                sender
+                    .send(text(ServerMessage::PowerDown {
+                        action: power_down.action,
+                        reason: Some(format!("Idle for {:?}", power_down.after)),
+                    }))
+                    .await?;
+            }
 
             tokio::select! {
                 r = sub.changed() => r?,
-                _ = tokio::time::sleep(Duration::from_secs(30)) => {},
+                _ = tokio::time::sleep(store.reevaluate_interval()) => {},
+                Some(msg) = relay.recv() => {
+                    tracing::debug!(?msg, "relaying message to builder");
+                    sender.send(text(msg)).await?;
+                }
             }
         }
-        #[allow(unreachable_code)]
-        Ok(())
     };
 
     let recv_handle = handle;
     let recv_task = async move {
-        // TODO: update last seen
-        while let Some(Ok(_msg)) = receiver.next().await {
-            match _msg {
-                Message::Text(_) | Message::Binary(_) | Message::Ping(_) | Message::Pong(_) => {
+        while let Some(Ok(msg)) = receiver.next().await {
+            match msg {
+                Message::Text(msg) => {
+                    recv_handle.heartbeat(Instant::now())?;
+                    match ClientMessage::try_from(msg.as_str()) {
+                        Ok(ClientMessage::Register { spec, token }) => {
+                            if spec.host_name == host_name {
+                                recv_store.register(spec, token.as_deref())?;
+                            } else {
+                                tracing::warn!(?spec.host_name, "ignoring registration for another host");
+                            }
+                        }
+                        Ok(ClientMessage::Status { awake }) => {
+                            tracing::debug!(awake, "{host_name} reported status");
+                        }
+                        Ok(ClientMessage::Load { load_average }) => {
+                            tracing::trace!(?load_average, "{host_name} reported load");
+                        }
+                        Ok(ClientMessage::Goodbye { reason }) => {
+                            tracing::info!(?reason, "{host_name} said goodbye");
+                            break;
+                        }
+                        Ok(msg @ ClientMessage::Hello { .. }) => {
+                            tracing::warn!(?msg, "ignoring unexpected message");
+                        }
+                        Err(err) => tracing::warn!(?msg, ?err, "Failed to parse message"),
+                    }
+                }
+                Message::Binary(_) | Message::Ping(_) | Message::Pong(_) => {
                     tracing::trace!("{host_name} sent heartbeat");
                     recv_handle.heartbeat(Instant::now())?;
                 }
@@ -83,7 +269,6 @@ async fn handle_socket(
                 }
             };
         }
-        #[allow(unreachable_code)]
         Ok(())
     };
 
@@ -0,0 +1,271 @@
+use crate::error::AppError;
+use axum::{
+    extract::connect_info::Connected,
+    serve::{self, IncomingStream},
+};
+use reqwest::StatusCode;
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::tls::certificate_names;
+
+/// Time allowed for a client to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Connection info of a remote peer
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Names from the peer's verified client certificate, if it presented one
+    pub certificate_names: Option<Vec<String>>,
+    /// Whether builders must present a client certificate. Other requests, e.g. webhooks, may
+    /// still connect without one.
+    pub require_certificate: bool,
+}
+
+impl Peer {
+    /// Check that the peer presented a client certificate for `host_name`, if required
+    pub fn verify_certificate(&self, host_name: &str) -> Result<(), AppError> {
+        match &self.certificate_names {
+            Some(names) if !names.iter().any(|name| name == host_name) => Err(AppError::from((
+                StatusCode::FORBIDDEN,
+                format!("Client certificate for {names:?} is not valid for {host_name}"),
+            ))),
+            None if self.require_certificate => Err(AppError::from((
+                StatusCode::FORBIDDEN,
+                format!("{host_name} must present a client certificate"),
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, Listener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, Listener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+/// Accepts plain TCP or TLS connections
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls {
+        local_addr: SocketAddr,
+        incoming: mpsc::Receiver<(Box<dyn Io>, Peer)>,
+    },
+}
+
+impl Listener {
+    /// Terminate TLS on connections accepted from `listener`. Handshakes are performed in the
+    /// background so that a slow client can't hold up others.
+    pub fn tls(
+        mut listener: TcpListener,
+        acceptor: TlsAcceptor,
+        require_certificate: bool,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, incoming) = mpsc::channel(32);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = serve::Listener::accept(&mut listener).await;
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => {
+                            let certificate_names = stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(|certs| certs.first())
+                                .map(certificate_names);
+                            let peer = Peer {
+                                addr,
+                                certificate_names,
+                                require_certificate,
+                            };
+                            let _ = sender.send((Box::new(stream) as _, peer)).await;
+                        }
+                        Ok(Err(err)) => tracing::debug!(?err, "TLS handshake with {addr} failed"),
+                        Err(_) => tracing::debug!("TLS handshake with {addr} timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Listener::Tls {
+            local_addr,
+            incoming,
+        })
+    }
+}
+
+impl serve::Listener for Listener {
+    type Io = Box<dyn Io>;
+    type Addr = Peer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = serve::Listener::accept(listener).await;
+                let peer = Peer {
+                    addr,
+                    certificate_names: None,
+                    require_certificate: false,
+                };
+                (Box::new(stream), peer)
+            }
+            Listener::Tls { incoming, .. } => incoming
+                .recv()
+                .await
+                .expect("TLS accept task to run forever"),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        let addr = match self {
+            Listener::Tcp(listener) => listener.local_addr()?,
+            Listener::Tls { local_addr, .. } => *local_addr,
+        };
+        Ok(Peer {
+            addr,
+            certificate_names: None,
+            require_certificate: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::TlsConfig, tls::CertResolver};
+    use axum::{Router, extract::ConnectInfo, routing::post};
+    use rustls::{
+        ClientConfig, DigitallySignedStruct, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    };
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    #[test]
+    fn verify_certificate() {
+        let peer = |certificate_names: Option<&[&str]>, require_certificate| Peer {
+            addr: "127.0.0.1:1234".parse().unwrap(),
+            certificate_names: certificate_names
+                .map(|names| names.iter().map(|name| name.to_string()).collect()),
+            require_certificate,
+        };
+        assert!(
+            peer(Some(&["bogus"]), true)
+                .verify_certificate("bogus")
+                .is_ok()
+        );
+        assert!(
+            peer(Some(&["other"]), true)
+                .verify_certificate("bogus")
+                .is_err()
+        );
+        assert!(peer(None, true).verify_certificate("bogus").is_err());
+        assert!(peer(None, false).verify_certificate("bogus").is_ok());
+    }
+
+    /// The test certificate is self-signed, and the server's identity isn't what's tested
+    #[derive(Debug)]
+    struct AcceptAnyServer;
+
+    impl ServerCertVerifier for AcceptAnyServer {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            rustls::crypto::ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_webhooks_without_client_certificate() {
+        let resolver = Arc::new(
+            CertResolver::new(TlsConfig {
+                cert_file: "test/tls/localhost.pem".into(),
+                key_file: "test/tls/localhost-key.pem".into(),
+                client_ca_file: Some("test/tls/ca.pem".into()),
+            })
+            .unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = Listener::tls(listener, resolver.acceptor().unwrap(), true).unwrap();
+        let app = Router::new().route(
+            "/webhook",
+            post(|ConnectInfo(peer): ConnectInfo<Peer>| async move {
+                format!("{:?}", peer.verify_certificate("bogus").is_ok())
+            }),
+        );
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<Peer>()).await
+        });
+
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyServer))
+                .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        stream
+            .write_all(
+                b"POST /webhook HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        // served, but not enough to connect as a builder
+        assert!(response.ends_with("false"), "{response}");
+    }
+}
@@ -1,10 +1,16 @@
 use crate::{
-    config::Config,
+    config::{Config, QueueConfig},
+    github::webhook::Webhook,
     hydra::{
         client::HydraClient,
-        store::{Store, generate_machines_file, wake_builders, watch_job_queue},
+        registration::Registration,
+        store::{Store, StoreConfig, generate_machines_file},
     },
-    middleware::allowed_ips,
+    listener::{Listener, Peer},
+    middleware::{AllowedIps, allowed_ips},
+    queue::{HydraApi, HydraDatabase, Manual, watch_queue},
+    tls::{CertResolver, watch_certificates},
+    wake::wake_builders,
 };
 use anyhow::Context;
 use axum::{Router, routing::get};
@@ -12,38 +18,90 @@ use axum::{Router, routing::get};
 use hydra_sentinel::shutdown_signal;
 use listenfd::ListenFd;
 use secrecy::SecretString;
-use std::{future::IntoFuture, net::SocketAddr, sync::Arc, time::Duration};
+use std::{future::IntoFuture, path::Path, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
 mod config;
 mod error;
+mod forge;
 mod github;
 mod hydra;
+mod listener;
 mod middleware;
 mod model;
+mod queue;
+mod tls;
+mod wake;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config = hydra_sentinel::init::<Config>(&format!("{}=DEBUG", module_path!()))?;
 
-    // TODO: Optional; disable webhooks without
-    let github_webhook_secret = std::fs::read_to_string(&config.github_webhook_secret_file)
-        .map(SecretString::from)
-        .context("Failed to read github webhook secret")?;
-
-    let hydra_client = HydraClient::new(config.hydra_base_url);
+    let hydra_client = HydraClient::new(config.hydra_base_url, config.hydra_auth)?;
+    let registration = Registration::load(config.registration)?;
+    let store = Arc::new(Store::new(
+        config.build_machines,
+        StoreConfig {
+            stale_after: config.heartbeat_timeout,
+            registration,
+            keep_awake: config.keep_awake,
+            reevaluate_interval: config.intervals.reevaluate,
+            wake_retries: config.wake_retries,
+        },
+    ));
 
     // build our application with some routes
-    let store = Arc::new(Store::new(config.heartbeat_timeout, config.build_machines));
-    let app = Router::new()
-        .route("/webhook", github::webhook::handler(github_webhook_secret))
-        .with_state(hydra_client.clone())
+
+    // webhooks are only served if configured
+    let mut app = Router::new();
+    let forge = forge::webhook::Forge::new(
+        hydra_client.clone(),
+        store.clone(),
+        config.webhook.expect_builds_for,
+    );
+    if let Some(path) = &config.github_webhook_secret_file {
+        let secret = std::fs::read_to_string(path)
+            .map(SecretString::from)
+            .context("Failed to read github webhook secret")?;
+        let webhook = Webhook::new(hydra_client.clone(), config.webhook, store.clone())?;
+        app = app.route(
+            "/webhook",
+            github::webhook::handler(secret).with_state(webhook),
+        );
+    }
+    if let Some(path) = &config.gitlab_webhook_token_file {
+        let token = read_secret(path).context("Failed to read gitlab webhook token")?;
+        app = app.route(
+            "/webhook/gitlab",
+            forge::webhook::gitlab_handler(token).with_state(forge.clone()),
+        );
+    }
+    if let Some(path) = &config.gitea_webhook_secret_file {
+        let secret = read_secret(path).context("Failed to read gitea webhook secret")?;
+        app = app.route(
+            "/webhook/gitea",
+            forge::webhook::gitea_handler(secret).with_state(forge.clone()),
+        );
+    }
+    if let Some(webhook) = config.generic_webhook {
+        let secret =
+            read_secret(&webhook.secret_file).context("Failed to read generic webhook secret")?;
+        app = app.route(
+            "/webhook/generic",
+            forge::webhook::generic_handler(secret).with_state((forge, Arc::new(webhook))),
+        );
+    }
+
+    let app = app
         .route(
             "/ws",
             get(hydra::websocket::connect).route_layer(axum::middleware::from_fn_with_state(
-                config.allowed_ips,
+                AllowedIps {
+                    networks: config.allowed_ips,
+                    any_with_certificate: config.allow_any_ip_with_certificate,
+                },
                 allowed_ips,
             )),
         )
@@ -67,22 +125,62 @@ async fn main() -> anyhow::Result<()> {
     };
 
     tracing::debug!("listening on {}", listener.local_addr()?);
-    let serve = axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_signal())
-    .into_future();
+    let cert_resolver = config.tls.map(CertResolver::new).transpose()?.map(Arc::new);
+    let listener = match &cert_resolver {
+        Some(resolver) => {
+            Listener::tls(listener, resolver.acceptor()?, resolver.verifies_clients())?
+        }
+        None => Listener::Tcp(listener),
+    };
+    let watch_certificates = async move {
+        match cert_resolver {
+            Some(resolver) => watch_certificates(resolver).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let serve = axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+        .with_graceful_shutdown({
+            let store = store.clone();
+            async move {
+                shutdown_signal().await;
+                store.shutdown();
+            }
+        })
+        .into_future();
 
-    let watch_job_queue = watch_job_queue(store.clone(), hydra_client);
+    let watch_queue = {
+        let store = store.clone();
+        let intervals = config.intervals;
+        async move {
+            match config.queue {
+                QueueConfig::HydraApi { max_builds } => {
+                    let source = HydraApi::new(hydra_client, store.clone(), max_builds);
+                    watch_queue(store, source, intervals).await
+                }
+                QueueConfig::HydraDatabase { connection } => {
+                    watch_queue(store, HydraDatabase::new(connection), intervals).await
+                }
+                QueueConfig::File { path } => {
+                    watch_queue(store, Manual::file(path), intervals).await
+                }
+                QueueConfig::Http { url } => watch_queue(store, Manual::http(url), intervals).await,
+            }
+        }
+    };
     let wake_builders = wake_builders(store.clone());
     let generate_machines_file = generate_machines_file(store, config.hydra_machines_file);
 
     tokio::select! {
         r = serve => { r?; },
-        r = watch_job_queue => { r?; },
+        r = watch_queue => { r?; },
         r = wake_builders => { r?; },
         r = generate_machines_file => { r?; },
+        r = watch_certificates => { r?; },
     };
     Ok(())
 }
+
+fn read_secret(path: &Path) -> std::io::Result<SecretString> {
+    std::fs::read_to_string(path).map(|secret| SecretString::from(secret.trim()))
+}
@@ -1,4 +1,4 @@
-use crate::error::AppError;
+use crate::{error::AppError, listener::Peer};
 use axum::{
     body::Body,
     extract::{ConnectInfo, State},
@@ -8,21 +8,62 @@ use axum::{
 };
 use ipnet::IpNet;
 use reqwest::StatusCode;
-use std::net::SocketAddr;
+
+/// Networks builders may connect from
+#[derive(Clone)]
+pub struct AllowedIps {
+    pub networks: Vec<IpNet>,
+    /// Whether builders presenting a verified client certificate may connect from anywhere
+    pub any_with_certificate: bool,
+}
+
+impl AllowedIps {
+    fn admits(&self, peer: &Peer) -> bool {
+        (self.any_with_certificate && peer.certificate_names.is_some())
+            || self
+                .networks
+                .iter()
+                .any(|ip| ip.contains(&IpNet::from(peer.addr.ip())))
+    }
+}
 
 pub async fn allowed_ips(
-    State(allowed): State<Vec<IpNet>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(allowed): State<AllowedIps>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
     request: Request<Body>,
     next: middleware::Next,
 ) -> Result<Response, AppError> {
-    if !allowed
-        .iter()
-        .any(|ip| ip.contains(&IpNet::from(addr.ip())))
-    {
-        tracing::info!("Denying connection from IP: {addr}");
+    if !allowed.admits(&peer) {
+        tracing::info!("Denying connection from IP: {}", peer.addr);
         return Ok(StatusCode::FORBIDDEN.into_response());
     }
 
     Ok(next.run(request).await)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificate_bypass_is_opt_in() {
+        let peer = |addr: &str, certificate_names: Option<Vec<String>>| Peer {
+            addr: addr.parse().unwrap(),
+            certificate_names,
+            require_certificate: true,
+        };
+        let mut allowed = AllowedIps {
+            networks: vec!["192.168.0.0/16".parse().unwrap()],
+            any_with_certificate: false,
+        };
+        let certified = Some(vec!["bogus".to_string()]);
+
+        assert!(allowed.admits(&peer("192.168.1.2:1234", None)));
+        assert!(!allowed.admits(&peer("10.0.0.2:1234", None)));
+        assert!(!allowed.admits(&peer("10.0.0.2:1234", certified.clone())));
+
+        allowed.any_with_certificate = true;
+        assert!(allowed.admits(&peer("10.0.0.2:1234", certified)));
+        assert!(!allowed.admits(&peer("10.0.0.2:1234", None)));
+    }
+}
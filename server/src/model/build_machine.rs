@@ -1,56 +1,55 @@
-use super::{MacAddress, System};
+use super::{BuildMachineSpec, MacAddress, QueuedBuild, WakeMethod, WakeOnLan, WakePolicy};
 use serde::Deserialize;
-use std::{
-    collections::{BTreeSet, HashSet},
-    fmt,
-};
+use std::{iter, path::PathBuf};
 
-/// A (Nix build machine)[https://nixos.org/manual/nix/stable/command-ref/conf-file#conf-builders] specification
 #[derive(Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct BuildMachineSpec {
-    pub ssh_user: Option<String>,
-    pub host_name: String,
-
-    /// A comma-separated list of [Nix system types](https://nixos.org/manual/nix/stable/contributing/hacking#system-type)
-    pub systems: BTreeSet<System>,
+pub struct BuildMachine {
+    #[serde(flatten)]
+    pub spec: BuildMachineSpec,
 
-    /// A path to the SSH identity file to be used to log in to the remote machine. If omitted, SSH will use its regular identities.
-    pub ssh_key: Option<String>,
+    #[serde(default)]
+    pub vms: Vec<BuildMachineSpec>,
 
-    /// The maximum number of builds that Nix will execute in parallel on the machine. Typically this should be equal to the number of CPU cores.
-    pub max_jobs: Option<u32>,
+    /// How to wake the builder. Defaults to wake-on-lan if `macAddress` is set.
+    pub wake: Option<WakeMethod>,
 
-    /// The "speed factor", indicating the relative speed of the machine as a positive integer. If there are multiple machines of the right type, Nix will prefer the fastest, taking load into account.
-    pub speed_factor: Option<u32>,
+    /// How to wake the builder if `wake` doesn't, see `wakeRetries.escalateAfter`
+    pub wake_fallback: Option<WakeMethod>,
 
-    /// A comma-separated list of supported [system features](https://nixos.org/manual/nix/stable/command-ref/conf-file#conf-system-features).
-    ///
-    /// A machine will only be used to build a derivation if all the features in the derivation's `requiredSystemFeatures` attribute are supported by that machine.
-    #[serde(default)]
-    pub supported_features: BTreeSet<String>,
+    /// Optional MAC address to trigger wake-on-lan
+    pub mac_address: Option<MacAddress>,
 
-    /// A comma-separated list of required [system features](https://nixos.org/manual/nix/stable/command-ref/conf-file#conf-system-features).
-    ///
-    /// A machine will only be used to build a derivation if all the features in the derivation's `requiredSystemFeatures` attribute are supported by that machine.
+    /// Where and how to send the wake-on-lan packet
     #[serde(default)]
-    pub mandatory_features: BTreeSet<String>,
+    pub wake_on_lan: WakeOnLan,
 
-    // The (base64-encoded) public host key of the remote machine. If omitted, SSH will use its regular known_hosts file.
-    pub public_host_key: Option<String>,
-}
+    /// Host name of a builder on the same network to send the wake-on-lan packet from, if the
+    /// server's packets can't reach this one. Inferred from a directed broadcast address if unset.
+    pub wake_via: Option<String>,
 
-#[derive(Deserialize, Clone, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct BuildMachine {
-    #[serde(flatten)]
-    pub spec: BuildMachineSpec,
+    /// File containing a secret shared with the builder. If set, the builder must sign its
+    /// connection requests with it.
+    pub token_file: Option<PathBuf>,
 
     #[serde(default)]
-    pub vms: Vec<BuildMachineSpec>,
+    pub policy: WakePolicy,
+}
 
-    /// Optional MAC address to trigger wake-on-lan
-    pub mac_address: Option<MacAddress>,
+impl From<BuildMachineSpec> for BuildMachine {
+    fn from(spec: BuildMachineSpec) -> Self {
+        BuildMachine {
+            spec,
+            vms: vec![],
+            wake: None,
+            wake_fallback: None,
+            mac_address: None,
+            wake_on_lan: WakeOnLan::default(),
+            wake_via: None,
+            token_file: None,
+            policy: WakePolicy::default(),
+        }
+    }
 }
 
 impl BuildMachine {
@@ -62,69 +61,58 @@ impl BuildMachine {
         self.mac_address
     }
 
-    // TODO: Separate config (this struct) from logic (store)
-    pub fn systems(&self) -> HashSet<System> {
-        let mut systems = self.spec.systems.iter().copied().collect::<HashSet<_>>();
-        for vm in &self.vms {
-            systems.extend(vm.systems.iter().copied());
+    /// How to wake the builder, if it can be woken
+    pub fn wake_method(&self) -> Option<&WakeMethod> {
+        match &self.wake {
+            Some(WakeMethod::WakeOnLan) | None => self.mac_address.map(|_| &WakeMethod::WakeOnLan),
+            Some(method) => Some(method),
         }
-        systems
     }
-}
 
-impl fmt::Display for BuildMachineSpec {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        macro_rules! write_field {
-            ($val: expr) => {
-                if let Some(val) = $val {
-                    write!(f, " {val}")?;
-                } else {
-                    write!(f, " -")?;
-                }
-            };
+    /// Fill in fields left unset in the config from the spec reported by the builder itself
+    pub fn with_reported(&self, reported: &BuildMachineSpec) -> BuildMachine {
+        let mut merged = self.clone();
+        let spec = &mut merged.spec;
+        if spec.ssh_user.is_none() {
+            spec.ssh_user.clone_from(&reported.ssh_user);
         }
-
-        macro_rules! write_list {
-            ($vals: expr) => {
-                let mut it = $vals.iter();
-                if let Some(val) = it.next() {
-                    write!(f, " {}", val)?;
-                    for val in it {
-                        write!(f, ",{}", val)?;
-                    }
-                } else {
-                    f.write_str(" -")?;
-                }
-            };
+        if spec.systems.is_empty() {
+            spec.systems.clone_from(&reported.systems);
         }
-
-        let BuildMachineSpec {
-            ssh_user,
-            host_name,
-            systems,
-            ssh_key,
-            max_jobs,
-            speed_factor,
-            supported_features,
-            mandatory_features,
-            public_host_key,
-        } = &self;
-
-        // hydra does not support ssh-ng; hard-coding
-        f.write_str("ssh://")?;
-        if let Some(user) = &ssh_user {
-            write!(f, "{user}@")?;
+        spec.max_jobs = spec.max_jobs.or(reported.max_jobs);
+        spec.speed_factor = spec.speed_factor.or(reported.speed_factor);
+        if spec.supported_features.is_empty() {
+            spec.supported_features
+                .clone_from(&reported.supported_features);
+        }
+        if spec.mandatory_features.is_empty() {
+            spec.mandatory_features
+                .clone_from(&reported.mandatory_features);
         }
-        f.write_str(host_name)?;
+        if spec.public_host_key.is_none() {
+            spec.public_host_key.clone_from(&reported.public_host_key);
+        }
+        merged
+    }
 
-        write_list!(systems);
-        write_list!(ssh_key);
-        write_field!(max_jobs);
-        write_field!(speed_factor);
-        write_list!(supported_features);
-        write_list!(mandatory_features);
-        write_field!(public_host_key);
+    /// Number of builds the builder and its VMs can run at once
+    pub fn capacity(&self) -> usize {
+        iter::once(&self.spec)
+            .chain(&self.vms)
+            .map(|spec| spec.max_jobs.unwrap_or(1) as usize)
+            .sum()
+    }
+
+    /// Relative speed, used to prefer faster builders
+    pub fn speed_factor(&self) -> u32 {
+        self.spec.speed_factor.unwrap_or(1)
+    }
 
-        Ok(())
+    // TODO: Separate config (this struct) from logic (store)
+    /// Whether the builder, or one of its VMs, can build `build`
+    pub fn can_build(&self, build: &QueuedBuild) -> bool {
+        iter::once(&self.spec)
+            .chain(&self.vms)
+            .any(|spec| spec.can_build(build.system, &build.required_features))
     }
 }
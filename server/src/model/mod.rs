@@ -1,5 +1,12 @@
 mod build_machine;
 mod mac_address;
-mod system;
+mod queued_build;
+mod wake_method;
+mod wake_on_lan;
+mod wake_policy;
 
-pub use self::{build_machine::*, mac_address::*, system::*};
+pub use self::{
+    build_machine::*, mac_address::*, queued_build::*, wake_method::*, wake_on_lan::*,
+    wake_policy::*,
+};
+pub use hydra_sentinel::model::{BuildMachineSpec, System};
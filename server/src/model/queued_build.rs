@@ -0,0 +1,74 @@
+use super::System;
+use std::{collections::BTreeSet, fmt};
+
+/// What a queued build needs from a builder
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct QueuedBuild {
+    pub system: System,
+    /// The derivation's `requiredSystemFeatures`
+    pub required_features: BTreeSet<String>,
+}
+
+impl QueuedBuild {
+    /// Parse the `requiredSystemFeatures` of a derivation in the store's ATerm format. Only
+    /// plain environment attributes are considered; derivations using `__structuredAttrs` are
+    /// treated as requiring no features.
+    pub fn from_derivation(system: System, drv: &str) -> Self {
+        const ATTR: &str = "(\"requiredSystemFeatures\",\"";
+        let required_features = drv
+            .split_once(ATTR)
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(value, _)| value.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+        QueuedBuild {
+            system,
+            required_features,
+        }
+    }
+}
+
+impl From<System> for QueuedBuild {
+    fn from(system: System) -> Self {
+        QueuedBuild {
+            system,
+            required_features: BTreeSet::new(),
+        }
+    }
+}
+
+impl fmt::Display for QueuedBuild {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.system)?;
+        if !self.required_features.is_empty() {
+            let features = self
+                .required_features
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            write!(f, " [{}]", features.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_derivation() {
+        let drv = r#"Derive([("out","/nix/store/aaaa-test","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("builder","/bin/sh"),("name","test"),("requiredSystemFeatures","kvm nixos-test"),("system","x86_64-linux")])"#;
+        let build = QueuedBuild::from_derivation(System::X86_64Linux, drv);
+        assert_eq!(
+            build.required_features,
+            ["kvm".to_string(), "nixos-test".to_string()].into()
+        );
+        assert_eq!(build.to_string(), "x86_64-linux [kvm,nixos-test]");
+
+        let drv = r#"Derive([("out","/nix/store/aaaa-test","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[("builder","/bin/sh"),("name","test"),("system","x86_64-linux")])"#;
+        assert_eq!(
+            QueuedBuild::from_derivation(System::X86_64Linux, drv),
+            QueuedBuild::from(System::X86_64Linux)
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::PathBuf};
+use url::Url;
+
+/// How to wake a builder
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "via", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum WakeMethod {
+    /// Send a magic packet to the builder's `macAddress`, as configured by `wakeOnLan` and
+    /// `wakeVia`
+    WakeOnLan,
+
+    /// Run a command, e.g. `["ipmitool", "-H", "bmc", "chassis", "power", "on"]`. The builder's
+    /// host name is passed in `HYDRA_SENTINEL_HOST_NAME`.
+    Command { command: Vec<String> },
+
+    /// Send an HTTP request, e.g. to switch on a smart plug through Home Assistant or Tasmota, or
+    /// to power on through Redfish. Credentials in the URL are sent using basic authentication.
+    Http {
+        url: Url,
+        #[serde(default = "default_http_method")]
+        method: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        body: Option<String>,
+        /// File containing a token to send as bearer token, e.g. a Home Assistant access token
+        token_file: Option<PathBuf>,
+    },
+
+    /// Start a libvirt domain with `virsh`
+    Libvirt {
+        domain: String,
+        /// Connection URI, e.g. `qemu:///system`
+        uri: Option<String>,
+    },
+}
+
+fn default_http_method() -> String {
+    "POST".to_string()
+}
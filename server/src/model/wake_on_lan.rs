@@ -0,0 +1,150 @@
+use super::MacAddress;
+use ipnet::{IpNet, Ipv4Net};
+use serde::{Deserialize, Deserializer, de};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+/// How to send a builder's wake-on-lan packet
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WakeOnLan {
+    /// Where to send the packet, e.g. the directed broadcast address of the builder's subnet
+    /// like `192.168.2.255` if it isn't on the same network as the server
+    pub broadcast_address: IpAddr,
+
+    /// UDP port to send the packet to, usually 9 (discard) or 7 (echo)
+    pub port: u16,
+
+    /// Local address to send the packet from, which selects the interface it goes out on
+    pub source_address: Option<IpAddr>,
+
+    /// Network interface to send the packet from, e.g. `eth1`. Only supported on Linux, and
+    /// requires `CAP_NET_RAW` on older kernels.
+    pub interface: Option<String>,
+
+    /// SecureOn password the builder's network card expects, as 4 or 6 hex bytes like
+    /// `01:23:45:67`
+    pub secure_on: Option<SecureOnPassword>,
+}
+
+impl Default for WakeOnLan {
+    fn default() -> Self {
+        Self {
+            broadcast_address: IpAddr::V4(Ipv4Addr::BROADCAST),
+            port: 9,
+            source_address: None,
+            interface: None,
+            secure_on: None,
+        }
+    }
+}
+
+impl WakeOnLan {
+    /// The magic packet waking `mac_address`, followed by the SecureOn password if any
+    pub fn packet(&self, mac_address: MacAddress) -> Vec<u8> {
+        hydra_sentinel::wake_on_lan::magic_packet(
+            mac_address.as_ref(),
+            self.secure_on.as_ref().map(AsRef::as_ref),
+        )
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        SocketAddr::new(self.broadcast_address, self.port)
+    }
+
+    /// The network the broadcast address is the directed broadcast address of, e.g.
+    /// `192.168.2.0/24` for `192.168.2.255`. Assumed to be no larger than a /24, since e.g.
+    /// `192.168.3.255` could be the broadcast address of a /24 or a /22.
+    pub fn network(&self) -> Option<IpNet> {
+        let IpAddr::V4(address) = self.broadcast_address else {
+            return None;
+        };
+        // networks smaller than /30 have no broadcast address
+        let host_bits = u32::from(address).trailing_ones();
+        if !(2..32).contains(&host_bits) {
+            return None;
+        }
+        let host_bits = host_bits.min(8);
+        let network = Ipv4Net::new(address, (32 - host_bits) as u8).ok()?;
+        Some(IpNet::V4(network.trunc()))
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecureOnPassword(Vec<u8>);
+
+impl AsRef<[u8]> for SecureOnPassword {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecureOnPassword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecureOnPassword(..)")
+    }
+}
+
+impl<'de> Deserialize<'de> for SecureOnPassword {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let password = String::deserialize(deserializer)?;
+        let bytes = password
+            .split([':', '-'])
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(de::Error::custom)?;
+        if !matches!(bytes.len(), 4 | 6) {
+            return Err(de::Error::invalid_length(
+                bytes.len(),
+                &"a SecureOn password of 4 or 6 bytes",
+            ));
+        }
+        Ok(SecureOnPassword(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secure_on_packet() {
+        let mac_address = serde_json::from_value("00:11:22:33:44:55".into()).unwrap();
+        let wake_on_lan = serde_json::from_value::<WakeOnLan>(serde_json::json!({
+            "broadcastAddress": "192.168.2.255",
+            "secureOn": "01:23:45:67",
+        }))
+        .unwrap();
+        assert_eq!(wake_on_lan.port, 9);
+
+        let packet = wake_on_lan.packet(mac_address);
+        assert_eq!(packet.len(), 6 + 16 * 6 + 4);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert_eq!(packet[6..12], [0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!(packet[102..], [0x01, 0x23, 0x45, 0x67]);
+
+        assert!(serde_json::from_value::<SecureOnPassword>("01:23:45".into()).is_err());
+    }
+
+    #[test]
+    fn directed_broadcast_network() {
+        let network = |broadcast_address: &str| {
+            WakeOnLan {
+                broadcast_address: broadcast_address.parse().unwrap(),
+                ..WakeOnLan::default()
+            }
+            .network()
+            .map(|network| network.to_string())
+        };
+        assert_eq!(network("192.168.2.255").as_deref(), Some("192.168.2.0/24"));
+        assert_eq!(network("10.0.0.127").as_deref(), Some("10.0.0.0/25"));
+        assert_eq!(network("192.168.3.255").as_deref(), Some("192.168.3.0/24"));
+        assert_eq!(network("255.255.255.255"), None);
+        assert_eq!(network("10.0.0.1"), None);
+    }
+}
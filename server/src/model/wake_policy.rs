@@ -0,0 +1,119 @@
+use chrono::NaiveTime;
+use hydra_sentinel::PowerAction;
+use serde::{Deserialize, Deserializer, de};
+use std::time::Duration;
+
+/// When, and how eagerly, a builder is woken and kept awake
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WakePolicy {
+    /// Builders with a higher priority are woken first
+    pub priority: i32,
+
+    /// Only wake the builder for builds that no other available builder can run
+    pub last_resort: bool,
+
+    /// Local time window in which the builder may be woken. Doesn't affect keeping an already
+    /// awake builder awake.
+    pub wake_hours: Option<WakeHours>,
+
+    /// Keep the builder awake for at least this long after it connects
+    #[serde(with = "humantime_serde")]
+    pub min_awake: Option<Duration>,
+
+    /// Neither wake the builder nor keep it awake once it's been connected this long today
+    #[serde(with = "humantime_serde")]
+    pub max_awake_per_day: Option<Duration>,
+
+    /// Overrides the global `keepAwake.idleGracePeriod`
+    #[serde(with = "humantime_serde")]
+    pub idle_grace_period: Option<Duration>,
+
+    /// Overrides the global `keepAwake.minAwakeAfterWake`
+    #[serde(with = "humantime_serde")]
+    pub min_awake_after_wake: Option<Duration>,
+
+    /// Ask the builder to power down once it's idle, instead of waiting for its OS to put it to
+    /// sleep
+    pub power_down: Option<PowerDown>,
+}
+
+/// How and when an idle builder is asked to power down. Its client must allow it, too.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerDown {
+    pub action: PowerAction,
+
+    /// How long the builder must have been idle, i.e. not kept awake, before it's powered down
+    #[serde(with = "humantime_serde")]
+    pub after: Duration,
+}
+
+impl WakePolicy {
+    pub fn may_wake(&self, time: NaiveTime, awake_today: Duration) -> bool {
+        self.wake_hours.is_none_or(|hours| hours.contains(time)) && !self.exhausted(awake_today)
+    }
+
+    /// Whether the builder has used up its awake time for the day
+    pub fn exhausted(&self, awake_today: Duration) -> bool {
+        self.max_awake_per_day.is_some_and(|max| awake_today >= max)
+    }
+}
+
+/// A daily time window, e.g. `{ "from": "07:00", "to": "22:00" }`. Wraps around midnight if
+/// `to` is before `from`.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct WakeHours {
+    #[serde(deserialize_with = "time_of_day")]
+    pub from: NaiveTime,
+    #[serde(deserialize_with = "time_of_day")]
+    pub to: NaiveTime,
+}
+
+impl WakeHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+fn time_of_day<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn wake_hours() {
+        let policy = serde_json::from_str::<WakePolicy>(
+            r#"{ "wakeHours": { "from": "22:00", "to": "07:00" }, "maxAwakePerDay": "2h" }"#,
+        )
+        .unwrap();
+
+        assert!(policy.may_wake(time("23:30"), Duration::ZERO));
+        assert!(policy.may_wake(time("06:59"), Duration::ZERO));
+        assert!(!policy.may_wake(time("07:00"), Duration::ZERO));
+        assert!(!policy.may_wake(time("12:00"), Duration::ZERO));
+        assert!(!policy.may_wake(time("23:30"), Duration::from_secs(2 * 60 * 60)));
+
+        let hours = WakeHours {
+            from: time("07:00"),
+            to: time("22:00"),
+        };
+        assert!(hours.contains(time("12:00")));
+        assert!(!hours.contains(time("23:00")));
+    }
+}
@@ -0,0 +1,281 @@
+use super::{QueueSource, RequiredFeatures, Snapshot};
+use crate::hydra::{
+    client::{Build, HydraClient},
+    store::Store,
+};
+use anyhow::Context;
+use futures_util::stream::{self, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// Most derivations to fetch per poll, the rest are fetched on later polls
+const MAX_LOOKUPS: usize = 100;
+
+/// Most derivations to fetch at once
+const CONCURRENT_LOOKUPS: usize = 8;
+
+/// Polls Hydra's JSON API for queued builds and running build steps. Builds with a running step
+/// are considered assigned to the machine running it rather than queued.
+///
+/// The queue doesn't include the builds' derivations, so they're fetched from `/build/<id>`,
+/// once per build and only if builders differ in their features. Their required system
+/// features can only be read if the server runs on the Hydra host, or otherwise has its store.
+pub struct HydraApi {
+    client: HydraClient,
+    store: Arc<Store>,
+    max_builds: Option<u32>,
+    required_features: RequiredFeatures,
+    /// Derivation of each queued build, `None` if Hydra didn't return one
+    drv_paths: HashMap<u64, Option<String>>,
+}
+
+impl HydraApi {
+    pub fn new(client: HydraClient, store: Arc<Store>, max_builds: Option<u32>) -> Self {
+        HydraApi {
+            client,
+            store,
+            max_builds,
+            required_features: RequiredFeatures::default(),
+            drv_paths: HashMap::new(),
+        }
+    }
+
+    /// Fetch the derivations of builds the queue didn't include them for, unless they were
+    /// fetched before. Failed lookups aren't retried either.
+    async fn fetch_drv_paths(&mut self, builds: &[Build]) {
+        let missing = builds
+            .iter()
+            .filter(|build| build.drvpath.is_none() && !self.drv_paths.contains_key(&build.id))
+            .map(|build| build.id)
+            .take(MAX_LOOKUPS)
+            .collect::<Vec<_>>();
+        let client = &self.client;
+        let fetched = stream::iter(missing)
+            .map(|id| async move {
+                let drv_path = client
+                    .get_drv_path(id)
+                    .await
+                    .inspect_err(|err| {
+                        tracing::debug!(?err, "Failed to fetch derivation of build {id}")
+                    })
+                    .ok()
+                    .flatten();
+                (id, drv_path)
+            })
+            .buffer_unordered(CONCURRENT_LOOKUPS)
+            .collect::<Vec<_>>()
+            .await;
+        self.drv_paths.extend(fetched);
+    }
+}
+
+impl QueueSource for HydraApi {
+    async fn fetch(&mut self) -> anyhow::Result<Snapshot> {
+        let queue = self
+            .client
+            .get_queue(self.max_builds)
+            .await
+            .context("Failed to poll queue")?;
+        let builds = queue.builds;
+        let steps = self
+            .client
+            .get_status()
+            .await
+            .context("Failed to poll running steps")?;
+        let running = steps.iter().map(|step| step.build).collect::<HashSet<_>>();
+
+        if self.store.features_differ() {
+            self.fetch_drv_paths(&builds).await;
+        }
+
+        let mut queued = Vec::with_capacity(builds.len());
+        let mut projects = HashMap::<_, HashSet<_>>::new();
+        let mut drv_paths = Vec::with_capacity(builds.len());
+        for build in &builds {
+            let drv_path = build
+                .drvpath
+                .as_deref()
+                .or(self.drv_paths.get(&build.id).and_then(Option::as_deref));
+            let kind = self
+                .required_features
+                .queued_build(build.system, drv_path)
+                .await;
+            drv_paths.extend(drv_path.map(str::to_string));
+            if !running.contains(&build.id) {
+                queued.push(kind.clone());
+            }
+            projects
+                .entry(build.project.clone())
+                .or_default()
+                .insert(kind);
+        }
+        if queue.truncated {
+            // the builds cut off could be of any kind seen before
+            tracing::warn!(
+                "Queue truncated to {} builds, assuming a backlog of every known kind of build",
+                builds.len()
+            );
+            let backlog = projects
+                .values()
+                .flatten()
+                .cloned()
+                .chain(self.store.recorded_builds())
+                .collect::<HashSet<_>>();
+            queued.extend(backlog);
+        }
+        self.required_features
+            .retain(drv_paths.iter().map(String::as_str));
+        let ids = builds.iter().map(|build| build.id).collect::<HashSet<_>>();
+        self.drv_paths.retain(|id, _| ids.contains(id));
+
+        Ok(Snapshot {
+            queued,
+            running: steps
+                .iter()
+                .map(|step| step.host_name().to_string())
+                .collect(),
+            projects,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::HydraAuth,
+        hydra::store::StoreConfig,
+        model::{BuildMachine, BuildMachineSpec, QueuedBuild, System},
+    };
+    use axum::{Json, Router, extract::Path, routing::get};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn fetch_derivations() {
+        let drv = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            &drv,
+            r#"Derive([("out","/nix/store/aaaa-test","","")],[],[],"aarch64-linux","/bin/sh",["-c","true"],[("builder","/bin/sh"),("name","test"),("requiredSystemFeatures","kvm"),("system","aarch64-linux")])"#,
+        )
+        .unwrap();
+        let drv_path = drv.path().to_str().unwrap().to_string();
+
+        // the recorded queue has no derivations
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/queue",
+                get(|| async { include_str!("../../test/hydra-queue.json") }),
+            )
+            .route("/status", get(|| async { Json(json!([])) }))
+            .route(
+                "/build/{id}",
+                get({
+                    let fetched = fetched.clone();
+                    move |Path(id): Path<u64>| async move {
+                        fetched.fetch_add(1, Ordering::Relaxed);
+                        Json(json!({ "drvpath": (id == 199).then_some(drv_path) }))
+                    }
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client =
+            HydraClient::new(format!("http://{addr}/").parse().unwrap(), HydraAuth::None).unwrap();
+        let builder = |host_name: &str, features: &[&str]| {
+            BuildMachine::from(BuildMachineSpec {
+                ssh_user: None,
+                host_name: host_name.into(),
+                ssh_key: None,
+                systems: [System::Aarch64Linux].into(),
+                supported_features: features.iter().map(|f| f.to_string()).collect(),
+                mandatory_features: Default::default(),
+                max_jobs: None,
+                speed_factor: None,
+                public_host_key: None,
+            })
+        };
+
+        // no point in fetching derivations if any builder would do
+        let store = Arc::new(Store::new(
+            [builder("bogus", &["kvm"]), builder("other", &["kvm"])],
+            StoreConfig::default(),
+        ));
+        let mut source = HydraApi::new(client.clone(), store, None);
+        let snapshot = source.fetch().await.unwrap();
+        assert!(!snapshot.queued.is_empty());
+        assert_eq!(fetched.load(Ordering::Relaxed), 0);
+
+        let store = Arc::new(Store::new(
+            [builder("bogus", &[]), builder("other", &["kvm"])],
+            StoreConfig::default(),
+        ));
+        let mut source = HydraApi::new(client, store, None);
+        let kvm = QueuedBuild {
+            system: System::Aarch64Linux,
+            required_features: ["kvm".to_string()].into(),
+        };
+
+        let snapshot = source.fetch().await.unwrap();
+        assert!(snapshot.queued.contains(&kvm));
+        assert!(
+            snapshot
+                .queued
+                .contains(&QueuedBuild::from(System::Aarch64Linux))
+        );
+        let requests = fetched.load(Ordering::Relaxed);
+        assert_eq!(requests, snapshot.queued.len());
+
+        // derivations are fetched once, even if Hydra had none
+        let snapshot = source.fetch().await.unwrap();
+        assert!(snapshot.queued.contains(&kvm));
+        assert_eq!(fetched.load(Ordering::Relaxed), requests);
+    }
+
+    #[tokio::test]
+    async fn truncated_queue() {
+        let app = Router::new()
+            .route(
+                "/api/queue",
+                get(|| async {
+                    let queue: Vec<serde_json::Value> =
+                        serde_json::from_str(include_str!("../../test/hydra-queue.json")).unwrap();
+                    Json(queue[..1].to_vec())
+                }),
+            )
+            .route("/status", get(|| async { Json(json!([])) }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client =
+            HydraClient::new(format!("http://{addr}/").parse().unwrap(), HydraAuth::None).unwrap();
+        let store = Arc::new(Store::new([], StoreConfig::default()));
+        store.record_projects(
+            [(
+                "other".to_string(),
+                [QueuedBuild::from(System::X86_64Linux)].into(),
+            )]
+            .into(),
+        );
+        let mut source = HydraApi::new(client, store, Some(1));
+
+        // builds of every kind seen before may have been cut off
+        let mut snapshot = source.fetch().await.unwrap();
+        snapshot.queued.sort();
+        assert_eq!(
+            snapshot.queued,
+            [
+                QueuedBuild::from(System::Aarch64Linux),
+                QueuedBuild::from(System::Aarch64Linux),
+                QueuedBuild::from(System::X86_64Linux),
+            ]
+        );
+    }
+}
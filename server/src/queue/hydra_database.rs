@@ -0,0 +1,170 @@
+use super::{QueueSource, RequiredFeatures, Snapshot, machine_host_name};
+use crate::model::System;
+use futures_util::stream::{self, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+
+/// Channels Hydra notifies on when builds are queued, started or finished
+const CHANNELS: &[&str] = &[
+    "builds_added",
+    "builds_restarted",
+    "builds_cancelled",
+    "builds_deleted",
+    "builds_bumped",
+    "build_started",
+    "build_finished",
+    "step_finished",
+];
+
+/// Reads the queue straight from Hydra's database, re-querying whenever Hydra notifies of a
+/// change
+pub struct HydraDatabase {
+    config: String,
+    connection: Option<Connection>,
+    required_features: RequiredFeatures,
+}
+
+struct Connection {
+    client: Client,
+    notifications: mpsc::UnboundedReceiver<()>,
+}
+
+impl HydraDatabase {
+    /// Connect using a PostgreSQL connection string, e.g. `host=/run/postgresql dbname=hydra`
+    pub fn new(config: String) -> Self {
+        HydraDatabase {
+            config,
+            connection: None,
+            required_features: RequiredFeatures::default(),
+        }
+    }
+}
+
+impl QueueSource for HydraDatabase {
+    async fn fetch(&mut self) -> anyhow::Result<Snapshot> {
+        // dropped on error, so that the next fetch reconnects
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => Connection::listen(&self.config).await?,
+        };
+
+        let snapshot = connection.query(&mut self.required_features).await?;
+        self.connection = Some(connection);
+        Ok(snapshot)
+    }
+
+    /// Re-query on notifications, and also after the poll interval, e.g. to see steps starting
+    async fn changed(&mut self, timeout: Duration) {
+        let Some(connection) = &mut self.connection else {
+            return tokio::time::sleep(timeout).await;
+        };
+        tokio::select! {
+            notification = connection.notifications.recv() => {
+                if notification.is_none() {
+                    tracing::warn!("Lost connection to Hydra database");
+                    self.connection = None;
+                    return;
+                }
+            }
+            _ = tokio::time::sleep(timeout) => {}
+        }
+        // a burst of notifications only needs one update
+        while connection.notifications.try_recv().is_ok() {}
+    }
+}
+
+impl Connection {
+    async fn listen(config: &str) -> anyhow::Result<Self> {
+        let (client, mut connection) = tokio_postgres::connect(config, NoTls).await?;
+
+        // the connection must be polled to make progress; forward notifications as it is
+        let (notify, notifications) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(notification)) => {
+                        tracing::trace!(channel = notification.channel(), "Notified");
+                        if notify.send(()).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!(?err, "Hydra database connection failed");
+                        break;
+                    }
+                }
+            }
+        });
+
+        let listen = CHANNELS
+            .iter()
+            .map(|channel| format!("LISTEN {channel};"))
+            .collect::<String>();
+        client.batch_execute(&listen).await?;
+        tracing::info!("Listening for queue changes in Hydra database");
+
+        Ok(Connection {
+            client,
+            notifications,
+        })
+    }
+
+    async fn query(&self, required_features: &mut RequiredFeatures) -> anyhow::Result<Snapshot> {
+        let builds = self
+            .client
+            .query(
+                "SELECT system, drvPath, project,
+                        EXISTS (SELECT 1 FROM BuildSteps s WHERE s.build = b.id AND s.busy != 0)
+                 FROM Builds b
+                 WHERE finished = 0",
+                &[],
+            )
+            .await?;
+        let steps = self
+            .client
+            .query("SELECT machine FROM BuildSteps WHERE busy != 0", &[])
+            .await?;
+
+        let mut queued = Vec::with_capacity(builds.len());
+        let mut drv_paths = Vec::with_capacity(builds.len());
+        let mut projects = HashMap::<_, HashSet<_>>::new();
+        for row in &builds {
+            let system = row.try_get::<_, &str>(0)?;
+            let Ok(system) = system.parse::<System>() else {
+                tracing::debug!("Ignoring build for unsupported system {system}");
+                continue;
+            };
+            let drv_path = row.try_get::<_, Option<&str>>(1)?;
+            let kind = required_features.queued_build(system, drv_path).await;
+            if !row.try_get::<_, bool>(3)? {
+                queued.push(kind.clone());
+            }
+            projects
+                .entry(row.try_get::<_, String>(2)?)
+                .or_default()
+                .insert(kind);
+            drv_paths.extend(drv_path);
+        }
+        required_features.retain(drv_paths);
+
+        let running = steps
+            .iter()
+            .map(|row| {
+                row.try_get::<_, &str>(0)
+                    .map(|machine| machine_host_name(machine).to_string())
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Snapshot {
+            queued,
+            running,
+            projects,
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use super::{QueueSource, Snapshot};
+use crate::model::{QueuedBuild, System};
+use anyhow::Context;
+use serde::Deserialize;
+use std::{collections::BTreeSet, iter, path::PathBuf};
+use url::Url;
+
+/// Demand described by hand, e.g. for testing or for build farms other than Hydra. Polls a JSON
+/// file or URL like
+///
+/// ```json
+/// {
+///   "queued": [{ "system": "x86_64-linux", "requiredFeatures": ["kvm"], "count": 2 }],
+///   "running": ["builder"]
+/// }
+/// ```
+pub enum Manual {
+    File(PathBuf),
+    Http(reqwest::Client, Url),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Demand {
+    #[serde(default)]
+    queued: Vec<QueuedDemand>,
+    /// Host names of builders with a build running
+    #[serde(default)]
+    running: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QueuedDemand {
+    system: System,
+    #[serde(default)]
+    required_features: BTreeSet<String>,
+    #[serde(default = "QueuedDemand::default_count")]
+    count: usize,
+}
+
+impl QueuedDemand {
+    fn default_count() -> usize {
+        1
+    }
+}
+
+impl From<Demand> for Snapshot {
+    fn from(demand: Demand) -> Self {
+        let queued = demand
+            .queued
+            .into_iter()
+            .flat_map(|queued| {
+                let build = QueuedBuild {
+                    system: queued.system,
+                    required_features: queued.required_features,
+                };
+                iter::repeat_n(build, queued.count)
+            })
+            .collect();
+        Snapshot {
+            queued,
+            running: demand.running,
+            ..Snapshot::default()
+        }
+    }
+}
+
+impl Manual {
+    pub fn file(path: PathBuf) -> Self {
+        Manual::File(path)
+    }
+
+    pub fn http(url: Url) -> Self {
+        Manual::Http(reqwest::Client::new(), url)
+    }
+}
+
+impl QueueSource for Manual {
+    async fn fetch(&mut self) -> anyhow::Result<Snapshot> {
+        let demand = match self {
+            Manual::File(path) => {
+                let demand = tokio::fs::read_to_string(&path)
+                    .await
+                    .with_context(|| format!("Failed to read {path:?}"))?;
+                serde_json::from_str::<Demand>(&demand)
+                    .with_context(|| format!("Failed to parse {path:?}"))?
+            }
+            Manual::Http(client, url) => client
+                .get(url.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Demand>()
+                .await
+                .with_context(|| format!("Failed to parse demand from {url}"))?,
+        };
+        Ok(Snapshot::from(demand))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            &file,
+            r#"{ "queued": [
+                { "system": "x86_64-linux", "requiredFeatures": ["kvm"], "count": 2 },
+                { "system": "aarch64-linux" }
+            ] }"#,
+        )
+        .unwrap();
+
+        let snapshot = Manual::file(file.path().to_path_buf())
+            .fetch()
+            .await
+            .unwrap();
+        let kvm = QueuedBuild {
+            system: System::X86_64Linux,
+            required_features: ["kvm".to_string()].into(),
+        };
+        assert_eq!(
+            snapshot,
+            Snapshot {
+                queued: vec![kvm.clone(), kvm, QueuedBuild::from(System::Aarch64Linux)],
+                running: vec![],
+                ..Snapshot::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Sources of demand for builders: what's queued, and what's running where
+
+mod hydra_api;
+mod hydra_database;
+mod manual;
+
+pub use self::{hydra_api::HydraApi, hydra_database::HydraDatabase, manual::Manual};
+
+use crate::{
+    config::IntervalConfig,
+    hydra::store::Store,
+    model::{QueuedBuild, System},
+};
+use backon::{BackoffBuilder, ExponentialBuilder};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The state of a queue at one point in time
+#[derive(Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub queued: Vec<QueuedBuild>,
+    /// Host names of the builders running each in-progress build step
+    pub running: Vec<String>,
+    /// Kinds of unfinished builds of each Hydra project, if the source knows
+    pub projects: HashMap<String, HashSet<QueuedBuild>>,
+}
+
+pub trait QueueSource {
+    /// Fetch the current state of the queue
+    async fn fetch(&mut self) -> anyhow::Result<Snapshot>;
+
+    /// Wait for the queue to (possibly) change, for at most `timeout`. Sources that can't be
+    /// notified of changes just wait out the timeout, i.e. are polled.
+    async fn changed(&mut self, timeout: Duration) {
+        tokio::time::sleep(timeout).await
+    }
+}
+
+/// Keep the store up to date with the queue. Failures are retried with exponential backoff.
+#[tracing::instrument(skip_all)]
+pub async fn watch_queue(
+    store: Arc<Store>,
+    mut source: impl QueueSource,
+    intervals: IntervalConfig,
+) -> anyhow::Result<Infallible> {
+    let backoff = ExponentialBuilder::default()
+        .with_min_delay(intervals.poll)
+        .with_max_delay(intervals.max_backoff)
+        .without_max_times()
+        .with_jitter();
+    let mut retries = backoff.build();
+    loop {
+        let delay = match source.fetch().await {
+            Ok(snapshot) => {
+                retries = backoff.build();
+                let idle = snapshot.queued.is_empty()
+                    && snapshot.running.is_empty()
+                    && !store.is_waking(Instant::now());
+                store.update_queued(snapshot.queued);
+                store.update_running(snapshot.running.iter().map(String::as_str));
+                store.record_projects(snapshot.projects);
+                match intervals.idle_poll {
+                    Some(idle_poll) if idle => idle_poll,
+                    _ => intervals.poll,
+                }
+            }
+            Err(err) => {
+                let delay = retries.next().unwrap_or(intervals.max_backoff);
+                tracing::warn!(?err, "Failed to update queue, retrying in {delay:?}");
+                delay
+            }
+        };
+        source.changed(delay).await;
+    }
+}
+
+/// Host name of a machine as listed in the machines file, e.g. `ssh://user@builder`
+pub fn machine_host_name(machine: &str) -> &str {
+    let machine = machine.split_once("://").map_or(machine, |(_, rest)| rest);
+    machine.rsplit_once('@').map_or(machine, |(_, host)| host)
+}
+
+/// Required system features of queued derivations. Derivations are immutable, so each only needs
+/// to be read once.
+#[derive(Default)]
+struct RequiredFeatures {
+    by_drv_path: HashMap<String, BTreeSet<String>>,
+}
+
+impl RequiredFeatures {
+    async fn queued_build(&mut self, system: System, drv_path: Option<&str>) -> QueuedBuild {
+        let Some(drv_path) = drv_path else {
+            return QueuedBuild::from(system);
+        };
+        if let Some(required_features) = self.by_drv_path.get(drv_path) {
+            return QueuedBuild {
+                system,
+                required_features: required_features.clone(),
+            };
+        }
+        match tokio::fs::read_to_string(drv_path).await {
+            Ok(drv) => {
+                let queued = QueuedBuild::from_derivation(system, &drv);
+                self.by_drv_path
+                    .insert(drv_path.to_string(), queued.required_features.clone());
+                queued
+            }
+            Err(err) => {
+                tracing::debug!(?err, "Failed to read {drv_path}, assuming no features");
+                QueuedBuild::from(system)
+            }
+        }
+    }
+
+    /// Forget derivations that are no longer queued
+    fn retain<'a>(&mut self, drv_paths: impl IntoIterator<Item = &'a str>) {
+        let drv_paths = drv_paths.into_iter().collect::<HashSet<_>>();
+        self.by_drv_path
+            .retain(|drv_path, _| drv_paths.contains(drv_path.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hydra::store::StoreConfig;
+    use std::collections::VecDeque;
+
+    /// Returns scripted results, recording when it was fetched, then never returns again
+    struct Scripted {
+        results: VecDeque<anyhow::Result<Snapshot>>,
+        fetched: Arc<std::sync::Mutex<Vec<tokio::time::Instant>>>,
+    }
+
+    impl QueueSource for Scripted {
+        async fn fetch(&mut self) -> anyhow::Result<Snapshot> {
+            self.fetched
+                .lock()
+                .unwrap()
+                .push(tokio::time::Instant::now());
+            match self.results.pop_front() {
+                Some(result) => result,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_delays() {
+        let store = Arc::new(Store::new(vec![], StoreConfig::default()));
+        let idle = || Ok(Snapshot::default());
+        let busy = || {
+            Ok(Snapshot {
+                queued: vec![QueuedBuild::from(System::X86_64Linux)],
+                ..Snapshot::default()
+            })
+        };
+        let failed = || Err(anyhow::anyhow!("Hydra is down"));
+        let fetched = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let source = Scripted {
+            results: [
+                idle(),
+                busy(),
+                failed(),
+                failed(),
+                failed(),
+                failed(),
+                failed(),
+                busy(),
+                failed(),
+                idle(),
+            ]
+            .into(),
+            fetched: fetched.clone(),
+        };
+        let intervals = IntervalConfig {
+            poll: Duration::from_secs(10),
+            idle_poll: Some(Duration::from_secs(60)),
+            max_backoff: Duration::from_secs(60),
+            ..IntervalConfig::default()
+        };
+
+        let watch = watch_queue(store, source, intervals);
+        let _ = tokio::time::timeout(Duration::from_secs(3600), watch).await;
+
+        let fetched = fetched.lock().unwrap();
+        let delays = fetched
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_secs())
+            .collect::<Vec<_>>();
+        // backoff doubles up to the maximum and is reset by a successful poll; jitter adds up to
+        // as much again
+        let expected = [60, 10, 10, 20, 40, 60, 60, 10, 10, 60];
+        assert_eq!(delays.len(), expected.len(), "{delays:?}");
+        for (i, (delay, expected)) in delays.iter().zip(expected).enumerate() {
+            let jittered = (2..=6).contains(&i) || i == 8;
+            let range = if jittered {
+                expected..expected * 2
+            } else {
+                expected..expected + 1
+            };
+            assert!(range.contains(delay), "{delays:?}");
+        }
+    }
+
+    #[test]
+    fn host_name() {
+        assert_eq!(machine_host_name("ssh://hydra@bogus"), "bogus");
+        assert_eq!(machine_host_name("ssh-ng://other"), "other");
+        assert_eq!(machine_host_name("localhost"), "localhost");
+    }
+}
@@ -0,0 +1,190 @@
+use crate::config::TlsConfig;
+use anyhow::Context;
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier, danger::ClientCertVerifier},
+    sign::CertifiedKey,
+};
+use std::{
+    convert::Infallible,
+    fmt, fs,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+/// Serves the most recently loaded certificate, so it can be replaced without a restart
+pub struct CertResolver {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl CertResolver {
+    pub fn new(config: TlsConfig) -> anyhow::Result<Self> {
+        let current = RwLock::new(Arc::new(load_certified_key(&config)?));
+        Ok(Self { config, current })
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> anyhow::Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.config.client_ca_file {
+            Some(path) => builder.with_client_cert_verifier(client_cert_verifier(path, provider)?),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Whether client certificates are verified, so builders must present one
+    pub fn verifies_clients(&self) -> bool {
+        self.config.client_ca_file.is_some()
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((
+            modified(&self.config.cert_file)?,
+            modified(&self.config.key_file)?,
+        ))
+    }
+}
+
+fn load_certified_key(config: &TlsConfig) -> anyhow::Result<CertifiedKey> {
+    let cert_chain = CertificateDer::pem_file_iter(&config.cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificate {:?}", config.cert_file))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_file)
+        .with_context(|| format!("Failed to read private key {:?}", config.key_file))?;
+    let key = ring::sign::any_supported_type(&key)?;
+    Ok(CertifiedKey::new(cert_chain, key))
+}
+
+fn client_cert_verifier(
+    path: &Path,
+    provider: Arc<CryptoProvider>,
+) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to read client CA {path:?}"))?
+    {
+        roots.add(cert?)?;
+    }
+    // webhooks are served on the same listener, so only /ws requires a certificate
+    Ok(
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .allow_unauthenticated()
+            .build()?,
+    )
+}
+
+/// Names a client certificate was issued for: its DNS subject alternative names, or its subject
+/// common names if it has none
+pub fn certificate_names(cert: &CertificateDer) -> Vec<String> {
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return vec![];
+    };
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        _ => vec![],
+    };
+    if !names.is_empty() {
+        return names;
+    }
+    cert.subject()
+        .iter_common_name()
+        .filter_map(|name| Some(name.as_str().ok()?.to_string()))
+        .collect()
+}
+
+/// Reload the certificate when its files change, e.g. after renewal
+#[tracing::instrument(skip_all)]
+pub async fn watch_certificates(resolver: Arc<CertResolver>) -> anyhow::Result<Infallible> {
+    let mut last_modified = resolver.modified();
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        let modified = resolver.modified();
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        match load_certified_key(&resolver.config) {
+            Ok(key) => {
+                *resolver.current.write().unwrap() = Arc::new(key);
+                tracing::info!("Reloaded TLS certificate");
+            }
+            Err(err) => tracing::error!(?err, "Failed to reload TLS certificate"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_certificate() {
+        let resolver = Arc::new(
+            CertResolver::new(TlsConfig {
+                cert_file: "test/tls/localhost.pem".into(),
+                key_file: "test/tls/localhost-key.pem".into(),
+                client_ca_file: Some("test/tls/ca.pem".into()),
+            })
+            .unwrap(),
+        );
+        assert_eq!(resolver.current.read().unwrap().cert.len(), 1);
+        resolver.acceptor().unwrap();
+
+        assert!(
+            CertResolver::new(TlsConfig {
+                cert_file: "test/tls/localhost.pem".into(),
+                key_file: "test/tls/missing.pem".into(),
+                client_ca_file: None,
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn client_certificate_names() {
+        let names = |path| {
+            let cert = CertificateDer::from_pem_file(path).unwrap();
+            certificate_names(&cert)
+        };
+        assert_eq!(names("test/tls/bogus.pem"), ["bogus"]);
+        // no subject alternative names; falls back to common name
+        assert_eq!(names("test/tls/other.pem"), ["other"]);
+        // only the subject's common name counts, not the issuer's
+        assert_eq!(names("test/tls/issued-by-bogus.pem"), ["relay"]);
+    }
+}
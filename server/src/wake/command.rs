@@ -0,0 +1,70 @@
+use anyhow::Context;
+use tokio::process::Command;
+
+/// Run `command`, failing unless it exits successfully
+pub async fn run(command: &[String], host_name: &str) -> anyhow::Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        anyhow::bail!("Empty wake command");
+    };
+    let output = Command::new(program)
+        .args(args)
+        .env("HYDRA_SENTINEL_HOST_NAME", host_name)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Command starting a libvirt domain
+pub fn virsh_start(domain: &str, uri: Option<&str>) -> Vec<String> {
+    let mut command = vec!["virsh".to_string()];
+    if let Some(uri) = uri {
+        command.extend(["--connect".to_string(), uri.to_string()]);
+    }
+    command.extend(["start".to_string(), domain.to_string()]);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn run_command() {
+        let command = |command: &[&str]| {
+            command
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>()
+        };
+        run(
+            &command(&["sh", "-c", r#"test "$HYDRA_SENTINEL_HOST_NAME" = bogus"#]),
+            "bogus",
+        )
+        .await
+        .unwrap();
+        assert!(
+            run(&command(&["sh", "-c", "echo nope >&2; exit 1"]), "bogus")
+                .await
+                .is_err()
+        );
+        assert!(run(&[], "bogus").await.is_err());
+    }
+
+    #[test]
+    fn libvirt() {
+        assert_eq!(
+            virsh_start("builder", Some("qemu:///system")),
+            ["virsh", "--connect", "qemu:///system", "start", "builder"]
+        );
+        assert_eq!(virsh_start("builder", None), ["virsh", "start", "builder"]);
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::Context;
+use reqwest::Method;
+use std::{collections::BTreeMap, path::Path, time::Duration};
+use url::Url;
+
+/// Send the configured request, failing on error responses or if it takes longer than `timeout`
+pub async fn send(
+    client: &reqwest::Client,
+    url: &Url,
+    method: &str,
+    headers: &BTreeMap<String, String>,
+    body: Option<&str>,
+    token_file: Option<&Path>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let method = Method::from_bytes(method.as_bytes())
+        .with_context(|| format!("Invalid HTTP method {method}"))?;
+    let mut request = client.request(method, url.clone()).timeout(timeout);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    if let Some(body) = body {
+        request = request.body(body.to_string());
+    }
+    if let Some(path) = token_file {
+        let token = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read token {path:?}"))?;
+        request = request.bearer_auth(token.trim());
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("{url} returned HTTP {status}: {body}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, http::HeaderMap, routing::post};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn send_request() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/api/services/switch/turn_on",
+            post(move |headers: HeaderMap, body: String| async move {
+                let authorization = headers
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                let _ = requests.send((authorization, body));
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let url = Url::parse(&format!(
+            "http://user:secret@{addr}/api/services/switch/turn_on"
+        ))
+        .unwrap();
+        let client = reqwest::Client::new();
+        let body = r#"{"entity_id": "switch.builder"}"#;
+        send(
+            &client,
+            &url,
+            "POST",
+            &BTreeMap::new(),
+            Some(body),
+            None,
+            TIMEOUT,
+        )
+        .await
+        .unwrap();
+        let (authorization, received_body) = received.recv().await.unwrap();
+        assert_eq!(authorization.as_deref(), Some("Basic dXNlcjpzZWNyZXQ="));
+        assert_eq!(received_body, body);
+
+        // no such route
+        assert!(
+            send(&client, &url, "GET", &BTreeMap::new(), None, None, TIMEOUT)
+                .await
+                .is_err()
+        );
+    }
+}
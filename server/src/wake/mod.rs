@@ -0,0 +1,126 @@
+//! Backends for waking offline builders
+
+mod command;
+mod http;
+mod wake_on_lan;
+
+use crate::{
+    hydra::store::{Store, WakeAttempt},
+    model::{BuildMachine, WakeMethod},
+};
+use futures_util::future::join_all;
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[tracing::instrument(skip_all)]
+pub async fn wake_builders(store: Arc<Store>) -> anyhow::Result<Infallible> {
+    let mut sub = store.subscribe();
+    // shared by all attempts, so connections are reused
+    let http = reqwest::Client::new();
+    loop {
+        tokio::select! {
+            r = sub.changed() => r?,
+            _ = tokio::time::sleep(store.reevaluate_interval()) => {},
+        }
+
+        // one slow builder doesn't hold up waking the others
+        let timeout = store.wake_attempt_timeout();
+        let attempts = store.wake_attempts(Instant::now());
+        join_all(
+            attempts
+                .into_iter()
+                .map(|attempt| try_wake(&store, &http, attempt, timeout)),
+        )
+        .await;
+    }
+}
+
+async fn try_wake(store: &Store, http: &reqwest::Client, attempt: WakeAttempt, timeout: Duration) {
+    let WakeAttempt {
+        builder,
+        method,
+        attempt,
+    } = attempt;
+    tracing::info!(
+        "Waking {} using {method:?}, attempt {attempt}",
+        builder.host_name()
+    );
+    if let Err(err) = wake(store, http, &builder, &method, timeout).await {
+        tracing::error!(?err, "Failed to wake {}", builder.host_name());
+        store.wake_failed(builder.host_name());
+    }
+}
+
+/// Wake `builder` using `method`, failing if that takes longer than `timeout`
+async fn wake(
+    store: &Store,
+    http: &reqwest::Client,
+    builder: &BuildMachine,
+    method: &WakeMethod,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    tokio::time::timeout(timeout, attempt(store, http, builder, method, timeout))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {timeout:?}"))?
+}
+
+async fn attempt(
+    store: &Store,
+    http: &reqwest::Client,
+    builder: &BuildMachine,
+    method: &WakeMethod,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    match method {
+        WakeMethod::WakeOnLan => wake_on_lan::wake(store, builder).await,
+        WakeMethod::Command { command } => command::run(command, builder.host_name()).await,
+        WakeMethod::Libvirt { domain, uri } => {
+            command::run(
+                &command::virsh_start(domain, uri.as_deref()),
+                builder.host_name(),
+            )
+            .await
+        }
+        WakeMethod::Http {
+            url,
+            method,
+            headers,
+            body,
+            token_file,
+        } => {
+            let (body, token_file) = (body.as_deref(), token_file.as_deref());
+            http::send(http, url, method, headers, body, token_file, timeout).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hydra::store::StoreConfig, model::BuildMachineSpec};
+
+    #[tokio::test]
+    async fn time_out() {
+        let store = Store::new(vec![], StoreConfig::default());
+        let builder = BuildMachine::from(
+            serde_json::from_value::<BuildMachineSpec>(
+                serde_json::json!({ "hostName": "bogus", "systems": ["x86_64-linux"] }),
+            )
+            .unwrap(),
+        );
+        let method = WakeMethod::Command {
+            command: vec!["sleep".to_string(), "10".to_string()],
+        };
+
+        let started = Instant::now();
+        let http = reqwest::Client::new();
+        let err = wake(&store, &http, &builder, &method, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{
+    hydra::store::Store,
+    model::{BuildMachine, MacAddress, WakeOnLan},
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+/// Send the builder's magic packet, through a connected builder on its network if there is one
+pub async fn wake(store: &Store, builder: &BuildMachine) -> anyhow::Result<()> {
+    let Some(mac_address) = builder.mac_address() else {
+        anyhow::bail!("No MAC address to wake {}", builder.host_name());
+    };
+    if let Some(relay) = store.relay_wake(builder) {
+        tracing::debug!("Asked {relay} to wake {}", builder.host_name());
+        return Ok(());
+    }
+    if let Some(wake_via) = &builder.wake_via {
+        tracing::warn!("{wake_via} isn't connected to wake {}", builder.host_name());
+    }
+    send(mac_address, &builder.wake_on_lan).await
+}
+
+/// Open a socket to send a wake-on-lan packet from
+fn socket(wake_on_lan: &WakeOnLan) -> anyhow::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let source = wake_on_lan
+        .source_address
+        .unwrap_or(match wake_on_lan.broadcast_address {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+    let socket = Socket::new(
+        Domain::for_address(SocketAddr::new(source, 0)),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if let Some(interface) = &wake_on_lan.interface {
+        #[cfg(target_os = "linux")]
+        socket.bind_device(Some(interface.as_bytes()))?;
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("Can't send from interface {interface}, only supported on Linux");
+    }
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(source, 0).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn send(mac_address: MacAddress, wake_on_lan: &WakeOnLan) -> anyhow::Result<()> {
+    let socket = socket(wake_on_lan)?;
+    let to_addr = wake_on_lan.target();
+    socket
+        .send_to(&wake_on_lan.packet(mac_address), to_addr)
+        .await?;
+    tracing::debug!("Sent WOL packet for {mac_address} to {to_addr}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn send_packet() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let wake_on_lan = WakeOnLan {
+            broadcast_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: receiver.local_addr().unwrap().port(),
+            source_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..WakeOnLan::default()
+        };
+        let mac_address = serde_json::from_value("00:11:22:33:44:55".into()).unwrap();
+        send(mac_address, &wake_on_lan).await.unwrap();
+
+        let mut packet = [0; 128];
+        let len = receiver.recv(&mut packet).await.unwrap();
+        assert_eq!(packet[..len], wake_on_lan.packet(mac_address));
+    }
+}